@@ -1,4 +1,6 @@
-#[derive(Default, Clone, PartialEq, Eq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BitSet256 {
     // bit[3] bit[2] bit[1] bit[0]
     bit: [u64; 4],
@@ -9,10 +11,39 @@ impl BitSet256 {
         self.bit[i / 64] ^= 1 << (i % 64);
     }
 
+    pub fn set(&mut self, i: usize) {
+        self.bit[i / 64] |= 1 << (i % 64);
+    }
+
     pub fn get(&self, i: usize) -> bool {
         (self.bit[i / 64] >> (i % 64) & 1) == 1
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.bit.iter().all(|&b| b == 0)
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bit.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    // iより小さい位置に立っているbitの数
+    pub fn rank(&self, i: usize) -> usize {
+        let mut r = 0;
+        for j in 0..i / 64 {
+            r += self.bit[j].count_ones() as usize;
+        }
+        if !i.is_multiple_of(64) {
+            r += (self.bit[i / 64] << (64 - i % 64)).count_ones() as usize;
+        }
+        r
+    }
+
+    // 立っているbitの位置を小さい順に
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..256).filter(move |&i| self.get(i))
+    }
+
     pub fn rot_left(&self, i: usize) -> Self {
         let mut b = self.clone();
         b.mut_rot_left(i);
@@ -60,15 +91,6 @@ impl BitSet256 {
             }
         }
     }
-
-    #[allow(clippy::needless_range_loop)]
-    pub fn to_vec(&self) -> Vec<bool> {
-        let mut val = vec![false; 256];
-        for i in 0..256 {
-            val[i] = self.get(i);
-        }
-        val
-    }
 }
 
 impl std::ops::BitOrAssign for BitSet256 {
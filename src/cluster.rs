@@ -0,0 +1,480 @@
+// 探索の単位を配るコーディネータと、受け取って探索するワーカー
+//
+// 1行1メッセージのテキストのプロトコル
+//   worker -> coordinator  HELLO <辞書のハッシュ> <オプションのハッシュ> <ターゲット,...>
+//   coordinator -> worker  OK <ハートビートの間隔(ミリ秒)> | ERROR <理由>
+//   worker -> coordinator  GET
+//   coordinator -> worker  UNIT <id> <単語の番号...> | WAIT | DONE
+//   worker -> coordinator  ALIVE  (単位を探索している間、間隔ごとに送る)
+//   worker -> coordinator  FOUND <id> <ターゲットの番号> <パスワード>
+//   worker -> coordinator  FINISHED <id> <ノード数>
+// FOUNDはFINISHEDが届いた時点で確定させる。
+// 接続が切れたワーカーや、--timeoutの間なにも送ってこないワーカーが持っていた単位は、
+// キューの先頭に戻して配り直す。
+// HELLOが一致するまでは、ほかのメッセージにERRORを返して接続を切る。
+// コーディネータは最後の単位が終わると接続を閉じて終了するので、
+// ワーカーはWAITや自分のFINISHEDのあとに接続が切れたら終わりとみなす
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cpu::PartialMemory;
+use crate::dict::{work_units, Dict, Searcher, END};
+use crate::domain::to_string;
+use crate::opt::{CoordinatorOpt, WorkerOpt, OPT};
+use crate::target::selected_targets;
+
+struct State {
+    units: Vec<Vec<usize>>,
+    queue: VecDeque<usize>,
+    finished: Vec<bool>,
+    finished_count: usize,
+    // (ターゲットの番号, パスワード)
+    found: BTreeSet<(usize, String)>,
+    nodes: u64,
+    start: Instant,
+}
+
+struct Coordinator {
+    state: Mutex<State>,
+    all_finished: Condvar,
+    // 終了するときに閉じる、ワーカーとの接続
+    connections: Mutex<Vec<TcpStream>>,
+    hello: String,
+    targets: usize,
+    timeout: Duration,
+}
+
+// 同じ辞書、同じオプション、同じターゲットで探索しているワーカーだけを受け付ける
+fn hello(dict: &Dict, targets: &[PartialMemory]) -> String {
+    let mut hasher = DefaultHasher::new();
+    dict.hash(&mut hasher);
+    format!(
+        "HELLO {:016x} {:016x} {}",
+        hasher.finish(),
+        OPT.search_hash(),
+        targets
+            .iter()
+            .map(|t| t.to_hex().replace(' ', ""))
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+fn encode_unit(unit: &[usize]) -> String {
+    unit.iter()
+        .map(|&w| {
+            if w == END {
+                "end".to_string()
+            } else {
+                w.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn decode_unit(s: &[&str]) -> Result<Vec<usize>, String> {
+    s.iter()
+        .map(|&w| {
+            if w == "end" {
+                Ok(END)
+            } else {
+                w.parse().map_err(|e| format!("{}: {}", w, e))
+            }
+        })
+        .collect()
+}
+
+fn handle(coordinator: &Coordinator, stream: TcpStream) -> Result<(), String> {
+    let peer = stream.peer_addr().map_err(|e| e.to_string())?;
+    // 短い行を交互にやりとりするので、Nagleで待たされないようにする
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(coordinator.timeout))
+        .map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    // serveも複製を持っているので、抜けるときは明示的に閉じる
+    let control = stream.try_clone().map_err(|e| e.to_string())?;
+    let mut writer = stream;
+    let mut send = |s: &str| writeln!(writer, "{}", s).map_err(|e| e.to_string());
+
+    let mut greeted = false;
+    let mut in_flight = HashSet::new();
+    let mut pending: HashMap<usize, Vec<(usize, String)>> = HashMap::new();
+
+    let result = (|| {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line).map_err(|e| match e.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                    format!("{}: no message for {:?}", peer, coordinator.timeout)
+                }
+                _ => format!("{}: {}", peer, e),
+            })?;
+            if n == 0 {
+                return Ok(());
+            }
+            let tokens: Vec<_> = line.split_whitespace().collect();
+
+            if !greeted && tokens.first() != Some(&"HELLO") {
+                send("ERROR send HELLO first")?;
+                return Err(format!("{}: {} before HELLO", peer, line.trim_end()));
+            }
+            match tokens[..] {
+                ["HELLO", ..] => {
+                    if line.trim_end() == coordinator.hello {
+                        greeted = true;
+                        send(&format!("OK {}", (coordinator.timeout / 3).as_millis()))?;
+                    } else {
+                        send("ERROR dictionary, options or targets differ")?;
+                        return Err(format!("{} has a different setup", peer));
+                    }
+                }
+                ["GET"] => {
+                    let mut state = coordinator.state.lock().unwrap();
+                    match state.queue.pop_front() {
+                        Some(id) => {
+                            in_flight.insert(id);
+                            send(&format!("UNIT {} {}", id, encode_unit(&state.units[id])))?;
+                        }
+                        None if state.finished_count == state.units.len() => send("DONE")?,
+                        // 他のワーカーが落ちたら配り直すので、まだ終わりではない
+                        None => send("WAIT")?,
+                    }
+                }
+                ["ALIVE"] => {}
+                ["FOUND", id, target, password] => {
+                    let id: usize = id.parse().map_err(|e| format!("{}: {}", id, e))?;
+                    let target: usize = target.parse().map_err(|e| format!("{}: {}", target, e))?;
+                    if !in_flight.contains(&id) || target >= coordinator.targets {
+                        return Err(format!("{}: invalid message {}", peer, line.trim_end()));
+                    }
+                    pending
+                        .entry(id)
+                        .or_default()
+                        .push((target, password.to_string()));
+                }
+                ["FINISHED", id, nodes] => {
+                    let id: usize = id.parse().map_err(|e| format!("{}: {}", id, e))?;
+                    let nodes: u64 = nodes.parse().map_err(|e| format!("{}: {}", nodes, e))?;
+                    // 渡していない単位は数えない
+                    if !in_flight.remove(&id) {
+                        return Err(format!("{}: unit {} was not given to it", peer, id));
+                    }
+
+                    let mut state = coordinator.state.lock().unwrap();
+                    if state.finished[id] {
+                        continue;
+                    }
+                    state.finished[id] = true;
+                    state.finished_count += 1;
+                    state.nodes += nodes;
+                    let found = pending.remove(&id).unwrap_or_default();
+                    for (target, password) in &found {
+                        println!("find: {} (target {})", password, target);
+                    }
+                    state.found.extend(found);
+                    eprintln!(
+                        "[{}/{}] unit {} by {}, {} found, {} nodes, {:.1}s",
+                        state.finished_count,
+                        state.units.len(),
+                        id,
+                        peer,
+                        state.found.len(),
+                        state.nodes,
+                        state.start.elapsed().as_secs_f64()
+                    );
+                    if state.finished_count == state.units.len() {
+                        coordinator.all_finished.notify_all();
+                    }
+                }
+                _ => return Err(format!("{}: unknown message {}", peer, line.trim_end())),
+            }
+        }
+    })();
+
+    // 終わっていない単位を配り直す
+    let mut state = coordinator.state.lock().unwrap();
+    for id in in_flight {
+        if !state.finished[id] {
+            eprintln!("requeue unit {} from {}", id, peer);
+            state.queue.push_front(id);
+        }
+    }
+    let _ = control.shutdown(Shutdown::Both);
+    result
+}
+
+// すべての単位が終わるまで配り、見つかったパスワードを返す
+fn serve(
+    listener: TcpListener,
+    units: Vec<Vec<usize>>,
+    hello: String,
+    targets: usize,
+    timeout: Duration,
+) -> BTreeSet<(usize, String)> {
+    let coordinator = Arc::new(Coordinator {
+        state: Mutex::new(State {
+            queue: (0..units.len()).collect(),
+            finished: vec![false; units.len()],
+            finished_count: 0,
+            units,
+            found: BTreeSet::new(),
+            nodes: 0,
+            start: Instant::now(),
+        }),
+        all_finished: Condvar::new(),
+        connections: Mutex::new(Vec::new()),
+        hello,
+        targets,
+        timeout,
+    });
+
+    {
+        let coordinator = coordinator.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Ok(stream) = stream.try_clone() {
+                    coordinator.connections.lock().unwrap().push(stream);
+                }
+                let coordinator = coordinator.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = handle(&coordinator, stream) {
+                        eprintln!("{}", e);
+                    }
+                });
+            }
+        });
+    }
+
+    let mut state = coordinator.state.lock().unwrap();
+    while state.finished_count < state.units.len() {
+        state = coordinator.all_finished.wait(state).unwrap();
+    }
+    // プロセスが終了したときと同じく、ワーカーとの接続を閉じる
+    for stream in coordinator.connections.lock().unwrap().drain(..) {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    std::mem::take(&mut state.found)
+}
+
+// 1行送って、1行受け取る
+fn request(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    s: &str,
+    line: &mut String,
+) -> Result<(), String> {
+    writeln!(writer, "{}", s).map_err(|e| e.to_string())?;
+    line.clear();
+    if reader.read_line(line).map_err(|e| e.to_string())? == 0 {
+        return Err("coordinator closed the connection".to_string());
+    }
+    Ok(())
+}
+
+// コーディネータから単位をもらえる限り探索する
+fn work(addr: &str, searcher: &mut Searcher, targets: &[PartialMemory]) -> Result<(), String> {
+    // 表を作るのに時間が掛かってもタイムアウトしないよう、つなぐ前に作っておく
+    let prepared = searcher.prepare(targets);
+
+    let stream = TcpStream::connect(addr).map_err(|e| format!("{}: {}", addr, e))?;
+    stream.set_nodelay(true).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let mut writer = stream;
+    let mut line = String::new();
+
+    let hello = hello(&searcher.dict, targets);
+    request(&mut reader, &mut writer, &hello, &mut line)?;
+    let heartbeat = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["OK", ms] => Duration::from_millis(ms.parse().map_err(|e| format!("{}: {}", ms, e))?),
+        _ => return Err(line.trim_end().to_string()),
+    };
+
+    // WAITや自分のFINISHEDのあと。ここで接続が切れたら、最後の単位が終わってコーディネータが終了した
+    let mut may_end = false;
+    loop {
+        match request(&mut reader, &mut writer, "GET", &mut line) {
+            Ok(()) => {}
+            Err(_) if may_end => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let tokens: Vec<_> = line.split_whitespace().collect();
+        match tokens[..] {
+            ["UNIT", id, ref unit @ ..] => {
+                let unit = decode_unit(unit)?;
+                // 探索している間も生きていることを知らせる
+                let mut alive = writer.try_clone().map_err(|e| e.to_string())?;
+                let results = std::thread::scope(|s| {
+                    let (done, stop) = mpsc::channel::<()>();
+                    s.spawn(move || {
+                        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(heartbeat) {
+                            if writeln!(alive, "ALIVE").is_err() {
+                                break;
+                            }
+                        }
+                    });
+                    let results = searcher.run_units(&prepared, &[unit]);
+                    drop(done);
+                    results
+                });
+
+                let mut report = String::new();
+                for (i, result) in results.iter().enumerate() {
+                    for password in &result.passwords {
+                        report += &format!("FOUND {} {} {}\n", id, i, to_string(password));
+                    }
+                }
+                report += &format!("FINISHED {} {}", id, results[0].nodes);
+                writeln!(writer, "{}", report).map_err(|e| e.to_string())?;
+                may_end = true;
+            }
+            ["WAIT"] => {
+                may_end = true;
+                std::thread::sleep(heartbeat.min(Duration::from_secs(1)));
+            }
+            ["DONE"] => return Ok(()),
+            _ => return Err(format!("unknown message {}", line.trim_end())),
+        }
+    }
+}
+
+pub fn coordinator(opt: &CoordinatorOpt) {
+    let targets = selected_targets();
+    assert!(
+        targets.iter().all(|t| t.len() == targets[0].len()),
+        "targets must have the same length"
+    );
+
+    let dict = Dict::new();
+    let units = work_units(&dict, opt.depth);
+    let listener = TcpListener::bind(&opt.listen)
+        .unwrap_or_else(|e| panic!("cannot listen on {}: {}", opt.listen, e));
+    eprintln!("listening on {}, {} units", opt.listen, units.len());
+
+    let found = serve(
+        listener,
+        units,
+        hello(&dict, &targets),
+        targets.len(),
+        Duration::from_secs(opt.timeout),
+    );
+    for (i, password) in found {
+        println!("{}\t{}", targets[i].to_hex(), password);
+    }
+}
+
+pub fn worker(opt: &WorkerOpt) {
+    let targets = selected_targets();
+    let mut searcher = Searcher::new();
+    work(&opt.connect, &mut searcher, &targets).unwrap_or_else(|e| panic!("{}", e));
+}
+
+#[test]
+fn distribute_over_localhost() {
+    let target: PartialMemory = "?? ?? 03 3A ?? ?? ?? 07".parse().unwrap();
+    let targets = vec![target];
+    let mut searcher = Searcher::new();
+    let expected: BTreeSet<_> = searcher
+        .search_many(&targets)
+        .pop()
+        .unwrap()
+        .passwords
+        .iter()
+        .map(|p| (0, to_string(p)))
+        .collect();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let units = work_units(&searcher.dict, 2);
+    let hello = hello(&searcher.dict, &targets);
+    let server =
+        std::thread::spawn(move || serve(listener, units, hello, 1, Duration::from_millis(600)));
+
+    // 単位を受け取る。受け取ったら、接続はそのまま返す
+    let take_unit = || {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut line = String::new();
+        writeln!(
+            writer,
+            "{}",
+            super::cluster::hello(&searcher.dict, &targets)
+        )
+        .unwrap();
+        reader.read_line(&mut line).unwrap();
+        writeln!(writer, "GET").unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("UNIT "), "{}", line);
+        (reader, writer)
+    };
+
+    // HELLOを送らないクライアントには単位を渡さない
+    {
+        let stream = TcpStream::connect(&addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        writeln!(writer, "GET").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("ERROR"), "{}", line);
+        line.clear();
+        assert_eq!(0, reader.read_line(&mut line).unwrap_or(0));
+    }
+
+    // 単位を受け取ったまま落ちるワーカー
+    drop(take_unit());
+    // 単位を受け取ったまま応答しなくなるワーカー。タイムアウトで切られる
+    let (mut silent, _writer) = take_unit();
+    // 渡していない単位の報告は受け付けない
+    {
+        let (mut reader, mut writer) = take_unit();
+        writeln!(writer, "FINISHED 999999 0").unwrap();
+        let mut line = String::new();
+        assert_eq!(0, reader.read_line(&mut line).unwrap_or(0));
+    }
+
+    let workers: Vec<_> = (0..2)
+        .map(|_| {
+            let addr = addr.clone();
+            let targets = targets.clone();
+            std::thread::spawn(move || work(&addr, &mut Searcher::new(), &targets).unwrap())
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(expected, server.join().unwrap());
+    let mut line = String::new();
+    assert_eq!(0, silent.read_line(&mut line).unwrap_or(0));
+}
+
+#[test]
+fn last_worker_ends_after_coordinator_exits() {
+    let target: PartialMemory = "?? ?? 03 3A ?? ?? ?? 07".parse().unwrap();
+    let targets = vec![target];
+    let searcher = Searcher::new();
+    let mut units = work_units(&searcher.dict, 1);
+    units.truncate(3);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let hello = hello(&searcher.dict, &targets);
+    let server =
+        std::thread::spawn(move || serve(listener, units, hello, 1, Duration::from_secs(60)));
+    let worker = std::thread::spawn(move || work(&addr, &mut Searcher::new(), &targets));
+
+    // 最後の単位のFINISHEDでコーディネータが接続を閉じて終わっても、ワーカーは正常に終わる
+    server.join().unwrap();
+    assert_eq!(Ok(()), worker.join().unwrap());
+}
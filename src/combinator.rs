@@ -0,0 +1,235 @@
+// 左右の辞書の単語をつなげた「単語 + 単語」「単語 + 区切り + 数字」のようなパスワードを探す
+//
+// ハッシュ値がすべて分かっていれば、右側(区切りを含む)を後ろからinverse_stepで戻して
+// 左側を読み終えたときに必要なMemoryの表を作り、左側を前から進めて表を引く。
+// 一部のバイトが分からなければ、左右のすべての組を前から進めて確かめる
+use std::collections::{BTreeMap, HashMap};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::composition::Limits;
+use crate::cpu::{forward_word, inverse_step, Memory, PartialMemory};
+use crate::dict::{build_validators, Dict, WordInfo};
+use crate::domain::{to_charcode_indices, to_string, CHAR_CODES};
+use crate::lm::NgramModel;
+use crate::opt::{CombineOpt, OPT};
+use crate::target::selected_targets;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Combination {
+    pub left: usize,
+    pub separator: usize,
+    pub right: usize,
+}
+
+// 右側を後ろから戻して、左側の長さごとに必要なMemoryと右側の組
+fn required_memories(
+    right: &Dict,
+    separators: &[Vec<usize>],
+    target: &Memory,
+) -> HashMap<(usize, Memory), Vec<(usize, usize)>> {
+    let mut required: HashMap<_, Vec<_>> = HashMap::new();
+    for (s, separator) in separators.iter().enumerate() {
+        for (r, word) in right.words.iter().enumerate() {
+            let suffix = [&separator[..], &word[..]].concat();
+            if suffix.len() >= target.len() {
+                continue;
+            }
+            let mut memories = vec![target.clone()];
+            for &c in suffix.iter().rev() {
+                memories = memories
+                    .iter()
+                    .flat_map(|m| inverse_step(m, CHAR_CODES[c]))
+                    .collect();
+            }
+            for memory in memories {
+                required
+                    .entry((target.len() - suffix.len(), memory))
+                    .or_default()
+                    .push((s, r));
+            }
+        }
+    }
+    required
+}
+
+// ハッシュ値がtargetになるパスワードと、それを作る左 + 区切り + 右の組
+// 区切りが辞書の単語でもあるときなど、同じパスワードが何通りかの組でできれば1つにまとめる
+pub fn combine(
+    left: &Dict,
+    right: &Dict,
+    separators: &[Vec<usize>],
+    target: &PartialMemory,
+) -> Vec<(Vec<usize>, Vec<Combination>)> {
+    let len = target.len();
+    let required = target
+        .to_exact()
+        .map(|exact| required_memories(right, separators, &exact));
+
+    let mut found: Vec<_> = (0..left.words.len())
+        .into_par_iter()
+        .flat_map_iter(|l| {
+            let word = &left.words[l];
+            let mut memory = Memory::new(len as u8);
+            forward_word(&mut memory, word);
+            let pairs: Vec<_> = match &required {
+                Some(required) => required
+                    .get(&(word.len(), memory))
+                    .cloned()
+                    .unwrap_or_default(),
+                None => separators
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(s, separator)| {
+                        right.words.iter().enumerate().filter_map({
+                            let memory = &memory;
+                            move |(r, right)| {
+                                if word.len() + separator.len() + right.len() != len {
+                                    return None;
+                                }
+                                let mut memory = memory.clone();
+                                forward_word(&mut memory, separator);
+                                forward_word(&mut memory, right);
+                                target.matches(&memory).then_some((s, r))
+                            }
+                        })
+                    })
+                    .collect(),
+            };
+            pairs
+                .into_iter()
+                .map(move |(separator, right)| Combination {
+                    left: l,
+                    separator,
+                    right,
+                })
+        })
+        .collect();
+    found.sort();
+
+    let mut passwords: BTreeMap<Vec<usize>, Vec<Combination>> = BTreeMap::new();
+    for c in found {
+        let password = [
+            &left.words[c.left][..],
+            &separators[c.separator][..],
+            &right.words[c.right][..],
+        ]
+        .concat();
+        passwords.entry(password).or_default().push(c);
+    }
+    passwords.into_iter().collect()
+}
+
+pub fn combinator_search(opt: &CombineOpt) {
+    let left = Dict::load(&opt.left);
+    let right = Dict::load(opt.right.as_ref().unwrap_or(&opt.left));
+    let separators = match &opt.separators {
+        Some(s) => s
+            .chars()
+            .map(|c| to_charcode_indices(&c.to_string()))
+            .collect(),
+        None => vec![vec![]],
+    };
+    // 単語の組み立て方の制約は、左右と区切りの単語をまとめた辞書で調べる
+    let mut words = Dict {
+        words: Vec::new(),
+        info: Vec::new(),
+    };
+    for dict in [&left, &right] {
+        words.words.extend(dict.words.iter().cloned());
+        words.info.extend(dict.info.iter().cloned());
+    }
+    for separator in separators.iter().filter(|s| !s.is_empty()) {
+        words.words.push(separator.clone());
+        words.info.push(WordInfo {
+            weight: 1.0,
+            tags: Vec::new(),
+            origin: None,
+        });
+    }
+    let validators = build_validators(&words, NgramModel::from_opt().as_ref(), Limits::from_opt());
+
+    for target in selected_targets() {
+        eprintln!("combining for {}", target.to_hex());
+        // 枝刈りは長さしか見ないので、ターゲットの代わりに渡す
+        let dummy = Memory::new(target.len() as u8);
+        let mut count = 0;
+        for (password, combinations) in combine(&left, &right, &separators, &target) {
+            // 枝刈りを通る組をすべて並べる
+            let mut origins = Vec::new();
+            for c in combinations {
+                let words = [
+                    &left.words[c.left][..],
+                    &separators[c.separator][..],
+                    &right.words[c.right][..],
+                ];
+                if !validators.accepts(&dummy, words.into_iter().filter(|w| !w.is_empty())) {
+                    continue;
+                }
+                let mut parts = vec![left.describe(c.left)];
+                if !separators[c.separator].is_empty() {
+                    parts.push(to_string(&separators[c.separator]));
+                }
+                parts.push(right.describe(c.right));
+                origins.push(parts.join(" + "));
+            }
+            if origins.is_empty() {
+                continue;
+            }
+            println!("find: {}\t{}", to_string(&password), origins.join(" | "));
+            count += 1;
+        }
+        eprintln!("{} passwords", count);
+        if OPT.verbose {
+            eprintln!(
+                "{} left words, {} separators, {} right words",
+                left.words.len(),
+                separators.len(),
+                right.words.len()
+            );
+        }
+    }
+}
+
+#[test]
+fn combine_left_and_right_words() {
+    let dict = |words: &[&str]| Dict {
+        words: words.iter().map(|w| to_charcode_indices(w)).collect(),
+        info: words
+            .iter()
+            .map(|_| WordInfo {
+                weight: 1.0,
+                tags: vec![],
+                origin: None,
+            })
+            .collect(),
+    };
+    let left = dict(&["HENTAI", "HENTA", "OSUGI", "818", "817"]);
+    let right = dict(&["OSUGI", "IOSUGI", "HENTAI", "6104", "6105"]);
+    // パスワードと、それを作る組の数
+    let passwords = |separators: &[Vec<usize>], target: &str| {
+        let target: PartialMemory = target.parse().unwrap();
+        combine(&left, &right, separators, &target)
+            .into_iter()
+            .map(|(password, combinations)| (to_string(&password), combinations.len()))
+            .collect::<Vec<_>>()
+    };
+
+    // 区切り方が違っても同じパスワードになる組は1つにまとめる
+    let none = [vec![]];
+    assert_eq!(
+        vec![("HENTAIOSUGI".to_string(), 2)],
+        passwords(&none, "64 98 0B 15 91 18 B1 15")
+    );
+    // 一部のバイトが分からなければ前から確かめる
+    assert_eq!(
+        vec![("HENTAIOSUGI".to_string(), 2)],
+        passwords(&none, "64 98 0B ?? 91 18 ?? 15")
+    );
+
+    let separators = [to_charcode_indices("-"), to_charcode_indices(".")];
+    assert_eq!(
+        vec![("818-6104".to_string(), 1)],
+        passwords(&separators, "DC D9 08 A3 E3 17 28 15")
+    );
+}
@@ -0,0 +1,210 @@
+// パスワードを辞書の単語でどう組み立てるかの制約
+// 1文字の単語ばかりでできた、意味のないパスワードを探索の途中で除外する
+use std::collections::HashSet;
+
+use crate::cpu::Memory;
+use crate::dict::Dict;
+use crate::domain::MAX_PASSWORD_LEN;
+use crate::opt::OPT;
+use crate::pruning::Validator;
+
+const IMPOSSIBLE: usize = usize::MAX;
+
+#[derive(Clone, Default)]
+pub struct Limits {
+    // 1文字の単語の数の上限
+    pub max_singles: Option<usize>,
+    // 一番長い単語の長さの下限
+    pub min_longest: Option<usize>,
+    // 単語の数の上限
+    pub max_words: Option<usize>,
+    // このタグの単語を1つ以上使う
+    pub require_tag: Option<String>,
+}
+
+impl Limits {
+    pub fn from_opt() -> Self {
+        Self {
+            max_singles: OPT.max_singles,
+            min_longest: OPT.min_longest,
+            max_words: OPT.max_words,
+            require_tag: OPT.require_tag.clone(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.max_singles.is_none()
+            && self.min_longest.is_none()
+            && self.max_words.is_none()
+            && self.require_tag.is_none()
+    }
+}
+
+// 状態は下位から8bitずつ単語の数、1文字の単語の数、一番長い単語の長さと、タグの単語を使ったかの1bit
+// 残りの文字数を埋めるのに最低限必要な単語の数で、この先守れなくなる枝を先に刈る
+pub struct Composition {
+    limits: Limits,
+    // require_tagの付いた単語
+    tagged: HashSet<Vec<usize>>,
+    min_tagged_len: usize,
+    // [残りの文字数] = 埋めるのに必要な単語の数の最小。埋められなければIMPOSSIBLE
+    min_words: Vec<usize>,
+    // [残りの文字数] = 埋めるのに必要な1文字の単語の数の最小
+    min_singles: Vec<usize>,
+}
+
+// 長さlensの単語を何個でも使って、ちょうどr文字にするときの単語の数の最小
+fn min_counts(lens: &HashSet<usize>, cost: impl Fn(usize) -> usize) -> Vec<usize> {
+    let mut min = vec![IMPOSSIBLE; MAX_PASSWORD_LEN + 1];
+    min[0] = 0;
+    for r in 1..=MAX_PASSWORD_LEN {
+        for &l in lens.iter().filter(|&&l| l <= r) {
+            if min[r - l] != IMPOSSIBLE {
+                min[r] = min[r].min(min[r - l] + cost(l));
+            }
+        }
+    }
+    min
+}
+
+impl Composition {
+    // 制約が1つもなければNone
+    pub fn new(dict: &Dict, limits: Limits) -> Option<Self> {
+        if limits.is_empty() {
+            return None;
+        }
+
+        let tagged: HashSet<_> = match &limits.require_tag {
+            Some(tag) => dict
+                .words
+                .iter()
+                .zip(&dict.info)
+                .filter(|(_, info)| info.tags.contains(tag))
+                .map(|(word, _)| word.clone())
+                .collect(),
+            None => HashSet::new(),
+        };
+        if let Some(tag) = &limits.require_tag {
+            assert!(!tagged.is_empty(), "no word is tagged #{}", tag);
+        }
+        let min_tagged_len = tagged.iter().map(|w| w.len()).min().unwrap_or(0);
+
+        let lens: HashSet<_> = dict.words.iter().map(|w| w.len()).collect();
+        let min_words = min_counts(&lens, |_| 1);
+        let min_singles = min_counts(&lens, |l| (l == 1) as usize);
+        Some(Self {
+            limits,
+            tagged,
+            min_tagged_len,
+            min_words,
+            min_singles,
+        })
+    }
+}
+
+impl Validator for Composition {
+    fn name(&self) -> &str {
+        "composition"
+    }
+
+    fn append(
+        &self,
+        expected_memory: &Memory,
+        password: &[usize],
+        _segmentation: &[usize],
+        state: u64,
+        append_word: &[usize],
+    ) -> Option<u64> {
+        let remain = expected_memory.len() - password.len() - append_word.len();
+        let words = (state & 0xff) as usize + 1;
+        let singles = (state >> 8 & 0xff) as usize + (append_word.len() == 1) as usize;
+        let longest = ((state >> 16 & 0xff) as usize).max(append_word.len());
+        let tagged = state >> 24 & 1 == 1 || self.tagged.contains(append_word);
+
+        if let Some(max) = self.limits.max_words {
+            if words.saturating_add(self.min_words[remain]) > max {
+                return None;
+            }
+        }
+        if let Some(max) = self.limits.max_singles {
+            if singles.saturating_add(self.min_singles[remain]) > max {
+                return None;
+            }
+        }
+        if let Some(min) = self.limits.min_longest {
+            if longest < min && remain < min {
+                return None;
+            }
+        }
+        if self.limits.require_tag.is_some() && !tagged && remain < self.min_tagged_len {
+            return None;
+        }
+
+        Some((tagged as u64) << 24 | (longest as u64) << 16 | (singles as u64) << 8 | words as u64)
+    }
+}
+
+#[test]
+fn composition_limits_with_remaining_length() {
+    use crate::dict::WordInfo;
+    use crate::domain::to_charcode_indices;
+    use crate::pruning::Validators;
+
+    let words = ["A", "B", "K", "KA", "HENTAI", "OSUGI"];
+    let dict = Dict {
+        words: words.iter().map(|w| to_charcode_indices(w)).collect(),
+        info: words
+            .iter()
+            .map(|&w| WordInfo {
+                weight: 1.0,
+                tags: if w == "HENTAI" {
+                    vec!["name".to_string()]
+                } else {
+                    vec![]
+                },
+                origin: None,
+            })
+            .collect(),
+    };
+    assert!(Composition::new(&dict, Limits::default()).is_none());
+
+    let accepts = |limits: Limits, segmentation: &[&str]| {
+        let mut validators = Validators::default();
+        validators.register(Box::new(Composition::new(&dict, limits).unwrap()));
+        let len: usize = segmentation.iter().map(|w| w.len()).sum();
+        let words: Vec<_> = segmentation
+            .iter()
+            .map(|w| to_charcode_indices(w))
+            .collect();
+        validators.accepts(&Memory::new(len as u8), words.iter().map(|w| &w[..]))
+    };
+    let max_singles = |n| Limits {
+        max_singles: Some(n),
+        ..Limits::default()
+    };
+    assert!(accepts(max_singles(1), &["HENTAI", "K", "KA"]));
+    assert!(!accepts(max_singles(1), &["HENTAI", "K", "A"]));
+    // 残り1文字は1文字の単語でしか埋められない
+    assert!(!accepts(max_singles(0), &["OSUGI", "KA", "A"]));
+
+    let min_longest = Limits {
+        min_longest: Some(5),
+        ..Limits::default()
+    };
+    assert!(accepts(min_longest.clone(), &["KA", "OSUGI"]));
+    assert!(!accepts(min_longest, &["KA", "KA", "KA"]));
+
+    let max_words = Limits {
+        max_words: Some(2),
+        ..Limits::default()
+    };
+    assert!(accepts(max_words.clone(), &["HENTAI", "OSUGI"]));
+    assert!(!accepts(max_words, &["KA", "KA", "A"]));
+
+    let require_tag = Limits {
+        require_tag: Some("name".to_string()),
+        ..Limits::default()
+    };
+    assert!(accepts(require_tag.clone(), &["KA", "HENTAI"]));
+    assert!(!accepts(require_tag, &["OSUGI", "KA", "K"]));
+}
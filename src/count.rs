@@ -0,0 +1,881 @@
+// 解の数え上げと、一様なサンプリング
+//
+// pattern2と同じ状態 (長さ, checkdigit2[0], checkdigit2[1], checkdigit5[0]) ごとに、
+// そこからターゲットの状態に到達する単語の並びの数を数えておく。
+// pattern2のbitが立っていない状態は0なので、立っている状態の分だけ持てばよい
+use std::collections::{HashMap, HashSet};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::cpu::{forward_word, Memory, PartialMemory};
+use crate::dict::{Dict, Searcher};
+use crate::domain::{to_string, try_to_charcode_indices, CHAR_CODES};
+use crate::opt::{LexCommand, SampleOpt};
+use crate::pattern::{build_pattern2, build_reachable, Pattern2, Transitions};
+use crate::pruning::satisfy_option_constraint;
+use crate::rng::SplitMix64;
+use crate::target::selected_targets;
+
+pub struct Counts {
+    len: usize,
+    pattern2: Pattern2,
+    // [len] の (checkdigit2[0], checkdigit2[1]) ごとに、pattern2のbitが立っているcheckdigit5[0]の小さい順
+    layers: Vec<HashMap<(u8, u8), Vec<u128>>>,
+}
+
+impl Counts {
+    pub fn new(dict: &Dict, transitions: &Transitions, pattern2: Pattern2, len: usize) -> Self {
+        eprintln!("calc counts");
+
+        // 枝刈りは長さしか見ない
+        let dummy = Memory::new(len as u8);
+
+        let mut layers = vec![HashMap::new(); len + 1];
+        for (s0, cells) in pattern2[len].iter().enumerate() {
+            for (s1, cell) in cells.iter().enumerate() {
+                if !cell.is_empty() {
+                    layers[len].insert((s0 as u8, s1 as u8), vec![1; cell.count_ones()]);
+                }
+            }
+        }
+
+        for l in (0..len).rev() {
+            let (current, next) = layers.split_at_mut(l + 1);
+            let count = |l2: usize, s0: usize, s1: usize, s2: usize| {
+                if !pattern2[l2][s0][s1].get(s2) {
+                    return 0;
+                }
+                next[l2 - l - 1][&(s0 as u8, s1 as u8)][pattern2[l2][s0][s1].rank(s2)]
+            };
+
+            current[l] = (0..0x100usize)
+                .into_par_iter()
+                .flat_map_iter(|s0| (0..0x100usize).map(move |s1| (s0, s1)))
+                .filter(|&(s0, s1)| !pattern2[l][s0][s1].is_empty())
+                .map(|(s0, s1)| {
+                    let cell = &pattern2[l][s0][s1];
+                    let mut counts = vec![0u128; cell.count_ones()];
+                    for (w, word) in dict.words.iter().enumerate() {
+                        if l + word.len() > len || !satisfy_option_constraint(&dummy, l, word) {
+                            continue;
+                        }
+
+                        let (next_s0, next_s1, offset) = transitions.get(w, s0, s1);
+                        for (c, s2) in counts.iter_mut().zip(cell.ones()) {
+                            let n = count(l + word.len(), next_s0, next_s1, (s2 + offset) & 0xFF);
+                            *c = c.checked_add(n).expect("count overflow");
+                        }
+                    }
+                    ((s0 as u8, s1 as u8), counts)
+                })
+                .collect();
+        }
+
+        Self {
+            len,
+            pattern2,
+            layers,
+        }
+    }
+
+    fn count(&self, l: usize, s0: usize, s1: usize, s2: usize) -> u128 {
+        let cell = &self.pattern2[l][s0][s1];
+        if !cell.get(s2) {
+            return 0;
+        }
+        self.layers[l][&(s0 as u8, s1 as u8)][cell.rank(s2)]
+    }
+
+    fn start(&self) -> (usize, usize, usize) {
+        let memory = Memory::new(self.len as u8);
+        (
+            memory.checkdigit2[0] as usize,
+            memory.checkdigit2[1] as usize,
+            memory.checkdigit5[0] as usize,
+        )
+    }
+
+    // pattern2の条件を満たす単語の並びの数
+    pub fn total(&self) -> u128 {
+        let (s0, s1, s2) = self.start();
+        self.count(0, s0, s1, s2)
+    }
+}
+
+// lexの候補。DP2の条件(checkdigit2とsum)を満たし、辞書の単語を並べて作れるパスワードで、解とは限らない
+//
+// 同じパスワードを1回だけ数えるため、辞書の単語を部品に分ける。部品は1文字の単語と、
+// 1文字の単語にない文字を含む単語。ほかの単語は1文字の単語を並べれば作れるので、
+// 部品を並べて作れるパスワードは辞書の単語を並べて作れるパスワードと同じになる。
+// 部品の並べ方がパスワードごとに1通りなら、部品の並びの数がそのままパスワードの数になる。
+// パスワードの辞書順は、1文字ずつ決めながら、そこまでを接頭辞に持つ部品の並びを数えて求める
+pub struct Candidates {
+    len: usize,
+    parts: Dict,
+    transitions: Transitions,
+    counts: Counts,
+    // 部品に出てくる文字を、文字列の順に並べたもの
+    chars: Vec<usize>,
+    // 文字ごとに、その文字で始まる部品
+    starts: Vec<Vec<usize>>,
+}
+
+// パスワードの接頭辞を部品に区切りながら読んだ状態。partがSome((a, k))なら、部品aのk文字目まで読んだところ
+#[derive(Clone, Copy)]
+struct Parse {
+    l: usize,
+    s0: usize,
+    s1: usize,
+    s2: usize,
+    part: Option<(usize, usize)>,
+}
+
+// 部品を並べて同じパスワードが2通りにできる組があるか(Sardinas-Pattersonの判定)
+// あれば、その2通りに出てくる部品の番号を返す
+fn ambiguous(parts: &[Vec<usize>]) -> Option<Vec<usize>> {
+    let code: HashMap<&[usize], usize> =
+        parts.iter().enumerate().map(|(a, p)| (&p[..], a)).collect();
+    // はみ出した残りごとに、1つ前の残り(最初は空)と、そこから作るのに使った部品
+    let mut seen: HashMap<Vec<usize>, (Vec<usize>, Vec<usize>)> = HashMap::new();
+    let mut queue = Vec::new();
+    for (a, u) in parts.iter().enumerate() {
+        for (b, v) in parts.iter().enumerate() {
+            if u.len() > v.len() && u.starts_with(v) && !seen.contains_key(&u[v.len()..]) {
+                seen.insert(u[v.len()..].to_vec(), (Vec::new(), vec![b, a]));
+                queue.push(u[v.len()..].to_vec());
+            }
+        }
+    }
+    while let Some(x) = queue.pop() {
+        if let Some(&a) = code.get(&x[..]) {
+            let mut words = vec![a];
+            let mut y = x;
+            while let Some((prev, used)) = seen.get(&y) {
+                words.extend(used);
+                y = prev.clone();
+            }
+            words.sort_unstable();
+            words.dedup();
+            return Some(words);
+        }
+        for (a, c) in parts.iter().enumerate() {
+            let rest = if x.len() > c.len() && x.starts_with(c) {
+                x[c.len()..].to_vec()
+            } else if c.len() > x.len() && c.starts_with(&x) {
+                c[x.len()..].to_vec()
+            } else {
+                continue;
+            };
+            if !seen.contains_key(&rest) {
+                seen.insert(rest.clone(), (x.clone(), vec![a]));
+                queue.push(rest);
+            }
+        }
+    }
+    None
+}
+
+impl Candidates {
+    pub fn build(searcher: &Searcher, target: &PartialMemory) -> Self {
+        let dict = &searcher.dict;
+        let len = target.len();
+        let singles: HashSet<usize> = dict
+            .words
+            .iter()
+            .filter(|w| w.len() == 1)
+            .map(|w| w[0])
+            .collect();
+        let mut parts = Dict {
+            words: Vec::new(),
+            info: Vec::new(),
+        };
+        for (w, word) in dict.words.iter().enumerate() {
+            if word.len() == 1 || word.iter().any(|c| !singles.contains(c)) {
+                parts.words.push(word.clone());
+                parts.info.push(dict.info[w].clone());
+            }
+        }
+        if let Some(words) = ambiguous(&parts.words) {
+            eprintln!(
+                "these words make the same password in two ways, so each password cannot be counted once: {}",
+                words
+                    .iter()
+                    .map(|&a| parts.describe(a))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+            std::process::exit(1);
+        }
+
+        let mut chars: Vec<usize> = parts.words.iter().flatten().copied().collect();
+        chars.sort_by_key(|&c| to_string(&[c]));
+        chars.dedup();
+        let mut starts = vec![Vec::new(); CHAR_CODES.len()];
+        for (a, part) in parts.words.iter().enumerate() {
+            starts[part[0]].push(a);
+        }
+
+        let transitions = Transitions::new(&parts);
+        let reachable = build_reachable(&parts, &transitions, len);
+        let pattern2 = build_pattern2(&parts, &transitions, &reachable, target);
+        let counts = Counts::new(&parts, &transitions, pattern2, len);
+        Self {
+            len,
+            parts,
+            transitions,
+            counts,
+            chars,
+            starts,
+        }
+    }
+
+    pub fn total(&self) -> u128 {
+        self.counts.total()
+    }
+
+    fn start(&self) -> Vec<Parse> {
+        let (s0, s1, s2) = self.counts.start();
+        vec![Parse {
+            l: 0,
+            s0,
+            s1,
+            s2,
+            part: None,
+        }]
+    }
+
+    // 部品aを読み終えたあとの状態
+    fn close(&self, p: &Parse, a: usize) -> Parse {
+        let (s0, s1, offset) = self.transitions.get(a, p.s0, p.s1);
+        Parse {
+            l: p.l + self.parts.words[a].len(),
+            s0,
+            s1,
+            s2: (p.s2 + offset) & 0xFF,
+            part: None,
+        }
+    }
+
+    // この状態から続けて候補になる部品の並びの数
+    fn count_parse(&self, p: &Parse) -> u128 {
+        let p = match p.part {
+            Some((a, _)) => self.close(p, a),
+            None => *p,
+        };
+        self.counts.count(p.l, p.s0, p.s1, p.s2)
+    }
+
+    fn count(&self, parses: &[Parse]) -> u128 {
+        parses.iter().map(|p| self.count_parse(p)).sum()
+    }
+
+    // 1文字読み進める。候補に続かない状態は捨てる
+    fn extend(&self, parses: &[Parse], c: usize) -> Vec<Parse> {
+        let dummy = Memory::new(self.len as u8);
+        let mut next = Vec::new();
+        for p in parses {
+            match p.part {
+                None => {
+                    for &a in &self.starts[c] {
+                        let part = &self.parts.words[a];
+                        if p.l + part.len() <= self.len
+                            && satisfy_option_constraint(&dummy, p.l, part)
+                        {
+                            next.push(Parse {
+                                part: Some((a, 1)),
+                                ..*p
+                            });
+                        }
+                    }
+                }
+                Some((a, k)) if self.parts.words[a][k] == c => next.push(Parse {
+                    part: Some((a, k + 1)),
+                    ..*p
+                }),
+                Some(_) => {}
+            }
+        }
+        for p in &mut next {
+            if let Some((a, k)) = p.part {
+                if k == self.parts.words[a].len() {
+                    *p = self.close(p, a);
+                }
+            }
+        }
+        next.retain(|p| self.count_parse(p) > 0);
+        next
+    }
+
+    // 候補をパスワードの辞書順に並べたときのr番目
+    pub fn nth(&self, mut r: u128) -> Vec<usize> {
+        assert!(r < self.total());
+
+        let mut parses = self.start();
+        let mut password = Vec::new();
+        while password.len() < self.len {
+            for &c in &self.chars {
+                let next = self.extend(&parses, c);
+                let n = self.count(&next);
+                if r < n {
+                    parses = next;
+                    password.push(c);
+                    break;
+                }
+                r -= n;
+            }
+        }
+        password
+    }
+
+    // nthの逆。候補でなければNone
+    pub fn rank(&self, password: &[usize]) -> Option<u128> {
+        let (r, parses) = self.position(password)?;
+        (self.count(&parses) > 0).then_some(r)
+    }
+
+    // passwordより辞書順で前にある候補の数と、passwordまで読んだ状態。長さが違えばNone
+    fn position(&self, password: &[usize]) -> Option<(u128, Vec<Parse>)> {
+        if password.len() != self.len {
+            return None;
+        }
+
+        let mut parses = self.start();
+        let mut r = 0;
+        for &c in password {
+            for &d in &self.chars {
+                if to_string(&[d]) >= to_string(&[c]) {
+                    break;
+                }
+                r += self.count(&self.extend(&parses, d));
+            }
+            parses = self.extend(&parses, c);
+        }
+        Some((r, parses))
+    }
+
+    // 辞書順で[begin, end)番目の候補を順に列挙する
+    // 範囲に掛からない接頭辞は数だけ見て飛ばす
+    pub fn for_each_in_range(&self, begin: u128, end: u128, f: &mut impl FnMut(u128, &[usize])) {
+        self.walk(&self.start(), 0, (begin, end), &mut Vec::new(), f);
+    }
+
+    fn walk(
+        &self,
+        parses: &[Parse],
+        base: u128,
+        range: (u128, u128),
+        password: &mut Vec<usize>,
+        f: &mut impl FnMut(u128, &[usize]),
+    ) {
+        if password.len() == self.len {
+            f(base, password);
+            return;
+        }
+
+        let mut base = base;
+        for &c in &self.chars {
+            if base >= range.1 {
+                return;
+            }
+
+            let next = self.extend(parses, c);
+            let n = self.count(&next);
+            if n > 0 && base + n > range.0 {
+                password.push(c);
+                self.walk(&next, base, range, password, f);
+                password.pop();
+            }
+            base += n;
+        }
+    }
+}
+
+// passwordを辞書の単語に区切る方法をすべて列挙する
+fn segmentations(dict: &Dict, password: &[usize]) -> Vec<Vec<usize>> {
+    let dummy = Memory::new(password.len() as u8);
+
+    fn rec(
+        dict: &Dict,
+        dummy: &Memory,
+        password: &[usize],
+        l: usize,
+        words: &mut Vec<usize>,
+        result: &mut Vec<Vec<usize>>,
+    ) {
+        if l == password.len() {
+            result.push(words.clone());
+            return;
+        }
+
+        for (w, word) in dict.words.iter().enumerate() {
+            if password[l..].starts_with(word) && satisfy_option_constraint(dummy, l, word) {
+                words.push(w);
+                rec(dict, dummy, password, l + word.len(), words, result);
+                words.pop();
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    rec(dict, &dummy, password, 0, &mut Vec::new(), &mut result);
+    result
+}
+
+// 探索で見つかる条件(いずれかの区切り方で枝刈りを通る)を満たすか
+fn passes_validators(searcher: &Searcher, segmentation: &[usize], dummy: &Memory) -> bool {
+    let words = segmentation.iter().map(|&w| &searcher.dict.words[w][..]);
+    searcher.validators.accepts(dummy, words)
+}
+
+// ターゲットの解なら、枝刈りを通る最初の区切り方
+fn solution_words(
+    searcher: &Searcher,
+    target: &PartialMemory,
+    password: &[usize],
+) -> Option<Vec<usize>> {
+    let mut memory = Memory::new(password.len() as u8);
+    forward_word(&mut memory, password);
+    if !target.matches(&memory) {
+        return None;
+    }
+    let dummy = Memory::new(target.len() as u8);
+    segmentations(&searcher.dict, password)
+        .into_iter()
+        .find(|s| passes_validators(searcher, s, &dummy))
+}
+
+// 1回に辿る候補の数。途中で止めたいときに、残りを辿る無駄をこの数までに抑える
+const SCAN_CHUNK: u128 = 1 << 16;
+
+// 候補を辞書順に[begin, end)番目まで確かめ、解を(候補の番号, パスワード, 区切り方)でfに渡す。
+// fがfalseを返したら止める
+fn scan_solutions(
+    searcher: &Searcher,
+    candidates: &Candidates,
+    target: &PartialMemory,
+    (begin, end): (u128, u128),
+    f: &mut impl FnMut(u128, &[usize], &[usize]) -> bool,
+) {
+    let mut i = begin;
+    while i < end {
+        let chunk_end = end.min(i.saturating_add(SCAN_CHUNK));
+        let mut stopped = false;
+        candidates.for_each_in_range(i, chunk_end, &mut |c, password| {
+            if stopped {
+                return;
+            }
+            if let Some(words) = solution_words(searcher, target, password) {
+                stopped = !f(c, password, &words);
+            }
+        });
+        if stopped {
+            return;
+        }
+        i = chunk_end;
+    }
+}
+
+// 辞書順で前からcandidate_end個の候補のうち、解になっているものの数
+fn count_solutions(
+    searcher: &Searcher,
+    candidates: &Candidates,
+    target: &PartialMemory,
+    candidate_end: u128,
+) -> u128 {
+    let mut n = 0;
+    scan_solutions(
+        searcher,
+        candidates,
+        target,
+        (0, candidate_end),
+        &mut |_, _, _| {
+            n += 1;
+            true
+        },
+    );
+    n
+}
+
+// 解をパスワードの辞書順に並べたときの[begin, begin + count)番目を、
+// (解の番号, パスワード, 区切り方)でfに渡す。
+// 解の番号は候補を先頭から確かめて数えるので、begin番目の解までの候補の数だけ時間が掛かる
+pub fn for_each_solution_in_range(
+    searcher: &Searcher,
+    candidates: &Candidates,
+    target: &PartialMemory,
+    begin: u128,
+    count: u128,
+    f: &mut impl FnMut(u128, &[usize], &[usize]),
+) {
+    if count == 0 {
+        return;
+    }
+    let end = begin.saturating_add(count);
+    let mut i = 0;
+    let range = (0, candidates.total());
+    scan_solutions(
+        searcher,
+        candidates,
+        target,
+        range,
+        &mut |_, password, words| {
+            if i >= begin {
+                f(i, password, words);
+            }
+            i += 1;
+            i < end
+        },
+    );
+}
+
+// passwordより辞書順で前にある解の数。長さが違えばNone
+pub fn solution_rank(
+    searcher: &Searcher,
+    candidates: &Candidates,
+    target: &PartialMemory,
+    password: &[usize],
+) -> Option<u128> {
+    let (end, _) = candidates.position(password)?;
+    Some(count_solutions(searcher, candidates, target, end))
+}
+
+pub struct Sampled {
+    pub passwords: Vec<Vec<usize>>,
+    pub attempts: usize,
+}
+
+// 候補のパスワードを一様に選び、ハッシュ値と枝刈りの条件で棄却する。
+// 候補はパスワードごとに1回だけ数えているので、解から一様に選んだことになる
+pub fn sample_passwords(
+    searcher: &Searcher,
+    candidates: &Candidates,
+    target: &PartialMemory,
+    rng: &mut SplitMix64,
+    samples: usize,
+    max_attempts: usize,
+) -> Sampled {
+    let dict = &searcher.dict;
+    let dummy = Memory::new(target.len() as u8);
+    let total = candidates.total();
+
+    let mut passwords = Vec::new();
+    let mut attempts = 0;
+    while total > 0 && passwords.len() < samples && attempts < max_attempts {
+        attempts += 1;
+
+        let password = candidates.nth(rng.below_u128(total));
+        let mut memory = Memory::new(password.len() as u8);
+        forward_word(&mut memory, &password);
+        if !target.matches(&memory) {
+            continue;
+        }
+
+        if segmentations(dict, &password)
+            .iter()
+            .any(|s| passes_validators(searcher, s, &dummy))
+        {
+            passwords.push(password);
+        }
+    }
+
+    Sampled {
+        passwords,
+        attempts,
+    }
+}
+
+// 1つ選ぶのに掛かる試行回数の見積もり
+// DP2が見ないcheckdigit5[1..]の分かっているバイトは、1バイトごとに候補のおよそ1/256しか通さない。
+// ターゲットには解が少なくとも1つあるとして、候補の数で頭打ちにする
+fn expected_attempts(target: &PartialMemory, total: u128) -> u128 {
+    let known = target.bytes[4..].iter().filter(|b| b.is_some()).count();
+    total.min(1 << (8 * known))
+}
+
+pub fn sample(opt: &SampleOpt) {
+    let searcher = Searcher::new();
+    let mut rng = SplitMix64::new(opt.seed);
+    for target in selected_targets() {
+        let candidates = Candidates::build(&searcher, &target);
+        let total = candidates.total();
+        eprintln!("{}: {} candidates", target.to_hex(), total);
+
+        let expected = expected_attempts(&target, total).saturating_mul(opt.samples as u128);
+        if expected > opt.max_attempts as u128 {
+            eprintln!(
+                "{}: {} samples would take about {} attempts (--max-attempts {}). \
+                 candidates only match checkdigit2 and sum, and most are rejected by the other known bytes; \
+                 replace some bytes with ?? or use the search instead",
+                target.to_hex(),
+                opt.samples,
+                expected,
+                opt.max_attempts
+            );
+            std::process::exit(1);
+        }
+
+        let sampled = sample_passwords(
+            &searcher,
+            &candidates,
+            &target,
+            &mut rng,
+            opt.samples,
+            opt.max_attempts,
+        );
+        for password in &sampled.passwords {
+            println!("{}", to_string(password));
+        }
+        eprintln!(
+            "{} samples in {} attempts",
+            sampled.passwords.len(),
+            sampled.attempts
+        );
+    }
+}
+
+// ターゲットの解になっているかと、パスワード、区切り方、解かどうかを並べた行
+// 区切り方は、解なら枝刈りを通る最初のもの、そうでなければ最初のもの
+fn describe_password(
+    searcher: &Searcher,
+    target: &PartialMemory,
+    password: &[usize],
+) -> (bool, String) {
+    let dict = &searcher.dict;
+    let passing = solution_words(searcher, target, password);
+    let solution = passing.is_some();
+    let words = passing
+        .or_else(|| segmentations(dict, password).into_iter().next())
+        .unwrap_or_default();
+    let line = format!(
+        "{}\t{}\t{}",
+        to_string(password),
+        describe_words(dict, &words),
+        if solution { "solution" } else { "-" }
+    );
+    (solution, line)
+}
+
+fn describe_words(dict: &Dict, words: &[usize]) -> String {
+    words
+        .iter()
+        .map(|&w| dict.describe(w))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// コマンドラインで渡されたパスワード。使えない文字があれば止まる
+fn parse_password(password: &str) -> Vec<usize> {
+    try_to_charcode_indices(password).unwrap_or_else(|| {
+        eprintln!("{} contains an invalid character", password);
+        std::process::exit(1);
+    })
+}
+
+pub fn lex(command: &LexCommand) {
+    let searcher = Searcher::new();
+    for target in selected_targets() {
+        let candidates = Candidates::build(&searcher, &target);
+
+        match command {
+            LexCommand::CountSolutions => {
+                let total = candidates.total();
+                let n = count_solutions(&searcher, &candidates, &target, total);
+                println!("{}\t{}", target.to_hex(), n)
+            }
+            LexCommand::NthSolution { index } => {
+                let mut found = false;
+                for_each_solution_in_range(
+                    &searcher,
+                    &candidates,
+                    &target,
+                    *index,
+                    1,
+                    &mut |i, password, words| {
+                        found = true;
+                        let words = describe_words(&searcher.dict, words);
+                        println!("{}\t{}\t{}", i, to_string(password), words);
+                    },
+                );
+                if !found {
+                    eprintln!("{}: index {} is out of range", target.to_hex(), index);
+                    std::process::exit(1);
+                }
+            }
+            LexCommand::SolutionRank { password } => {
+                let password = parse_password(password);
+                match solution_rank(&searcher, &candidates, &target, &password) {
+                    Some(rank) => println!("{}", rank),
+                    None => println!("not the length of {}", target.to_hex()),
+                }
+            }
+            LexCommand::SolutionRange { begin, count } => {
+                let mut n = 0;
+                for_each_solution_in_range(
+                    &searcher,
+                    &candidates,
+                    &target,
+                    *begin,
+                    *count,
+                    &mut |i, password, words| {
+                        n += 1;
+                        let words = describe_words(&searcher.dict, words);
+                        println!("{}\t{}\t{}", i, to_string(password), words);
+                    },
+                );
+                eprintln!("{} solutions from {}", n, begin);
+            }
+            LexCommand::CountCandidates => {
+                println!("{}\t{}", target.to_hex(), candidates.total())
+            }
+            LexCommand::NthCandidate { index } => {
+                if *index >= candidates.total() {
+                    eprintln!(
+                        "index {} is out of range (total {})",
+                        index,
+                        candidates.total()
+                    );
+                    std::process::exit(1);
+                }
+                let password = candidates.nth(*index);
+                println!(
+                    "{}\t{}",
+                    index,
+                    describe_password(&searcher, &target, &password).1
+                );
+            }
+            LexCommand::CandidateRank { password } => {
+                let password = parse_password(password);
+                match candidates.rank(&password) {
+                    Some(rank) => println!("{}", rank),
+                    None => println!("not a candidate of {}", target.to_hex()),
+                }
+            }
+            LexCommand::CandidateRange { begin, count, all } => {
+                let end = begin.saturating_add(*count).min(candidates.total());
+                let mut solutions = 0;
+                candidates.for_each_in_range(*begin, end, &mut |i, password| {
+                    let (solution, line) = describe_password(&searcher, &target, password);
+                    if solution {
+                        solutions += 1;
+                    } else if !all {
+                        return;
+                    }
+                    println!("{}\t{}", i, line);
+                });
+                eprintln!("{} solutions in [{}, {})", solutions, begin, end);
+            }
+        }
+    }
+}
+
+#[test]
+fn counts_match_enumeration() {
+    let target: PartialMemory = "00 51 03 3A ?? ?? ?? ??".parse().unwrap();
+    let mut searcher = Searcher::new();
+    let reachable = searcher.reachable(3);
+    let pattern2 = build_pattern2(&searcher.dict, &searcher.transitions, &reachable, &target);
+    let counts = Counts::new(&searcher.dict, &searcher.transitions, pattern2, 3);
+
+    // 長さ3になる単語の並びを総当たりする。nは「単語なし」
+    let dict = &searcher.dict;
+    let n = dict.words.len();
+    let mut expected = Vec::new();
+    for a in 0..n {
+        for b in 0..=n {
+            for c in 0..=n {
+                if b == n && c < n {
+                    continue;
+                }
+                let words: Vec<_> = [a, b, c].into_iter().filter(|&w| w < n).collect();
+                let password: Vec<_> = words.iter().flat_map(|&w| dict.words[w].clone()).collect();
+                let mut memory = Memory::new(3);
+                forward_word(&mut memory, &password);
+                if password.len() == 3 && target.matches(&memory) {
+                    expected.push(words);
+                }
+            }
+        }
+    }
+    assert_eq!(expected.len() as u128, counts.total());
+}
+
+#[test]
+fn sample_exact_target() {
+    use crate::domain::to_charcode_indices;
+
+    let kid: PartialMemory = "00 51 03 3A E9 08 23 07".parse().unwrap();
+    let searcher = Searcher::new();
+    let candidates = Candidates::build(&searcher, &kid);
+    assert!(expected_attempts(&kid, candidates.total()) <= candidates.total());
+    let mut rng = SplitMix64::new(0);
+    let sampled = sample_passwords(&searcher, &candidates, &kid, &mut rng, 5, 10000);
+    assert_eq!(5, sampled.passwords.len());
+    assert!(sampled.passwords.iter().all(|p| to_string(p) == "KID"));
+
+    // 解はKIDだけなので、前にある解の数はKIDまで0、KIDより後で1になる
+    let total = candidates.total();
+    assert_eq!(1, count_solutions(&searcher, &candidates, &kid, total));
+    let mut solutions = Vec::new();
+    for_each_solution_in_range(&searcher, &candidates, &kid, 0, 10, &mut |i, p, _| {
+        solutions.push((i, to_string(p)))
+    });
+    assert_eq!(vec![(0, "KID".to_string())], solutions);
+    let rank = |p: &str| solution_rank(&searcher, &candidates, &kid, &to_charcode_indices(p));
+    assert_eq!(Some(0), rank("KID"));
+    assert_eq!(Some(0), rank("AAA"));
+    assert_eq!(Some(1), rank("KIE"));
+    assert_eq!(None, rank("KIDS"));
+
+    // 長いターゲットは候補が多く、分かっているバイト1つごとに試行が256倍になる
+    let long: PartialMemory = "DC D9 0E A3 ?? ?? 28 15".parse().unwrap();
+    assert_eq!(1 << 16, expected_attempts(&long, 1 << 40));
+}
+
+#[test]
+fn candidates_in_password_order() {
+    use crate::domain::to_charcode_indices;
+
+    let target: PartialMemory = "?? ?? 03 3A ?? ?? ?? ??".parse().unwrap();
+    let searcher = Searcher::new();
+    let candidates = Candidates::build(&searcher, &target);
+
+    // 長さ3のパスワードを総当たりする。1文字の単語にないn, m, cはnmcとしてしか使えない
+    let dict = &searcher.dict;
+    let singles: Vec<_> = dict.words.iter().filter(|w| w.len() == 1).collect();
+    let mut expected = vec![to_charcode_indices("nmc")];
+    for a in &singles {
+        for b in &singles {
+            for c in &singles {
+                expected.push([&a[..], &b[..], &c[..]].concat());
+            }
+        }
+    }
+    expected.retain(|password| {
+        let mut memory = Memory::new(3);
+        forward_word(&mut memory, password);
+        target.matches(&memory)
+    });
+    expected.sort_by_key(|p| to_string(p));
+
+    assert_eq!(expected.len() as u128, candidates.total());
+    let mut ranged = Vec::new();
+    candidates.for_each_in_range(0, candidates.total(), &mut |i, password| {
+        ranged.push((i, password.to_vec()));
+    });
+    for (r, password) in expected.iter().enumerate() {
+        assert_eq!((r as u128, password.clone()), ranged[r]);
+        assert_eq!(password, &candidates.nth(r as u128));
+        assert_eq!(Some(r as u128), candidates.rank(password));
+    }
+    assert_eq!(None, candidates.rank(&to_charcode_indices("nnn")));
+
+    let parts = |words: &[&str]| {
+        words
+            .iter()
+            .map(|w| to_charcode_indices(w))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(None, ambiguous(&parts(&["A", "B", "nmc", "nA"])));
+    // AnA = An A = A nA
+    assert_eq!(Some(vec![0, 1, 2]), ambiguous(&parts(&["A", "nA", "An"])));
+}
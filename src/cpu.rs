@@ -1,4 +1,4 @@
-use crate::domain::CHAR_CODES;
+use crate::domain::{to_string, try_to_charcode_indices, CHAR_CODES, MAX_PASSWORD_LEN};
 
 struct Cpu {
     reg: Register,
@@ -35,6 +35,259 @@ impl Memory {
     pub fn len(&self) -> usize {
         self.password_len as usize
     }
+
+    // yokai03.exeと同じ並び($31F4 ~ $31FB)
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        bytes[0..2].copy_from_slice(&self.checkdigit2);
+        bytes[2] = self.password_len;
+        bytes[3..8].copy_from_slice(&self.checkdigit5);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self {
+            checkdigit2: [bytes[0], bytes[1]],
+            password_len: bytes[2],
+            checkdigit5: [bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]],
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.to_bytes()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // ROMやダンプから拾ってきた値が、パスワードのハッシュ値としてありえるかを検証する
+    pub fn validate(&self) -> Result<(), String> {
+        PartialMemory::from(self.clone()).validate()
+    }
+}
+
+// yokai03.exeの引数と同じ、空白区切りの16進8バイト
+impl std::str::FromStr for Memory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<_> = s.split_whitespace().collect();
+        if tokens.len() != 8 {
+            return Err(format!("expected 8 bytes, got {}: {}", tokens.len(), s));
+        }
+
+        let mut bytes = [0; 8];
+        for (b, t) in bytes.iter_mut().zip(tokens) {
+            *b = u8::from_str_radix(t, 16).map_err(|e| format!("{}: {}", t, e))?;
+        }
+        Ok(Self::from_bytes(bytes))
+    }
+}
+
+// 一部のバイトだけが分かっているハッシュ値。並びはMemory::to_bytesと同じで、Noneは何でもよい
+// 長さが分からないと探索できないので、長さだけは必須
+#[derive(PartialEq, Eq, Clone, Hash)]
+pub struct PartialMemory {
+    pub bytes: [Option<u8>; 8],
+}
+
+impl From<Memory> for PartialMemory {
+    fn from(memory: Memory) -> Self {
+        Self {
+            bytes: memory.to_bytes().map(Some),
+        }
+    }
+}
+
+impl PartialMemory {
+    pub fn len(&self) -> usize {
+        self.bytes[2].unwrap() as usize
+    }
+
+    // 全バイトが分かっていればMemoryとして返す
+    pub fn to_exact(&self) -> Option<Memory> {
+        let mut bytes = [0; 8];
+        for (b, p) in bytes.iter_mut().zip(&self.bytes) {
+            *b = (*p)?;
+        }
+        Some(Memory::from_bytes(bytes))
+    }
+
+    // i番目のバイトとしてありえる値
+    pub fn candidates(&self, i: usize) -> Vec<usize> {
+        match self.bytes[i] {
+            Some(b) => vec![b as usize],
+            None => (0..0x100).collect(),
+        }
+    }
+
+    pub fn checkdigit2(&self) -> [Vec<usize>; 2] {
+        [self.candidates(0), self.candidates(1)]
+    }
+
+    pub fn sum(&self) -> Vec<usize> {
+        self.candidates(3)
+    }
+
+    pub fn xor(&self) -> Vec<usize> {
+        self.candidates(5)
+    }
+
+    pub fn bit(&self) -> Vec<usize> {
+        match self.bytes[7] {
+            Some(b) => vec![b as usize],
+            None => (0..=self.max_bit()).collect(),
+        }
+    }
+
+    // bitが取りうる最大値。分からなければ長さから決まる上限
+    pub fn max_bit(&self) -> usize {
+        match self.bytes[7] {
+            Some(b) => b as usize,
+            None => self.len() * max_bit_per_char(),
+        }
+    }
+
+    pub fn matches(&self, memory: &Memory) -> bool {
+        self.bytes
+            .iter()
+            .zip(memory.to_bytes())
+            .all(|(p, b)| p.is_none_or(|p| p == b))
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.bytes
+            .iter()
+            .map(|b| match b {
+                Some(b) => format!("{:02X}", b),
+                None => "??".to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.len() == 0 || self.len() > MAX_PASSWORD_LEN {
+            return Err(format!("invalid password length {}", self.len()));
+        }
+
+        if let Some(bit) = self.bytes[7] {
+            if bit as usize > self.len() * max_bit_per_char() {
+                return Err(format!(
+                    "bit count {} is unreachable with length {}",
+                    bit,
+                    self.len()
+                ));
+            }
+        }
+
+        // sumは桁あふれするまでは、文字コードとキャリーの和より大きくならない
+        if let Some(sum) = self.bytes[3] {
+            let max = self.len() * (*CHAR_CODES.iter().max().unwrap() as usize + 1);
+            if sum as usize > max {
+                return Err(format!(
+                    "sum 0x{:02X} is unreachable with length {}",
+                    sum,
+                    self.len()
+                ));
+            }
+        }
+
+        if let Some(xor) = self.bytes[5] {
+            if xor >= 0x40 || reachable_xors(self.len()) >> xor & 1 == 0 {
+                return Err(format!(
+                    "xor 0x{:02X} is unreachable with length {}",
+                    xor,
+                    self.len()
+                ));
+            }
+        }
+
+        // 短いパスワードは全部試せる
+        if self.len() <= EXHAUSTIVE_LEN && !self.matches_some_password() {
+            return Err(format!(
+                "no password of length {} has this hash",
+                self.len()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn matches_some_password(&self) -> bool {
+        let len = self.len();
+        (0..CHAR_CODES.len().pow(len as u32)).any(|mut n| {
+            let mut memory = Memory::new(len as u8);
+            for _ in 0..len {
+                forward_step(&mut memory, CHAR_CODES[n % CHAR_CODES.len()]);
+                n /= CHAR_CODES.len();
+            }
+            self.matches(&memory)
+        })
+    }
+
+    // 判明しているパスワードが本当にこのハッシュ値になるか
+    pub fn verify_solution(&self, password: &str) -> Result<(), String> {
+        let password = try_to_charcode_indices(password)
+            .ok_or_else(|| format!("{} contains an invalid character", password))?;
+        let mut memory = Memory::new(password.len() as u8);
+        forward_word(&mut memory, &password);
+        if !self.matches(&memory) {
+            return Err(format!(
+                "{} does not match {}",
+                to_string(&password),
+                self.to_hex()
+            ));
+        }
+        Ok(())
+    }
+}
+
+// 1文字でbitが増える量は、文字コードの1の数 + キャリーが上限
+fn max_bit_per_char() -> usize {
+    CHAR_CODES.iter().map(|c| c.count_ones()).max().unwrap() as usize + 1
+}
+
+// validateでこの長さまでは、すべてのパスワードを試して確かめる
+const EXHAUSTIVE_LEN: usize = 3;
+
+// 長さlenのパスワードでありえるxor(checkdigit5[2])の集合。文字コードは0x40未満なので、u64のbitで持つ
+fn reachable_xors(len: usize) -> u64 {
+    let mut xors = 1u64;
+    for _ in 0..len {
+        let mut next = 0;
+        for x in (0..64).filter(|x| xors >> x & 1 == 1) {
+            for &c in &CHAR_CODES {
+                next |= 1 << (x ^ c as usize);
+            }
+        }
+        xors = next;
+    }
+    xors
+}
+
+// Memoryと同じ書式で、分からないバイトは??と書く
+impl std::str::FromStr for PartialMemory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<_> = s.split_whitespace().collect();
+        if tokens.len() != 8 {
+            return Err(format!("expected 8 bytes, got {}: {}", tokens.len(), s));
+        }
+
+        let mut bytes = [None; 8];
+        for (b, t) in bytes.iter_mut().zip(tokens) {
+            if t != "??" {
+                *b = Some(u8::from_str_radix(t, 16).map_err(|e| format!("{}: {}", t, e))?);
+            }
+        }
+        if bytes[2].is_none() {
+            return Err(format!("password length must be known: {}", s));
+        }
+        Ok(Self { bytes })
+    }
 }
 
 impl std::fmt::Debug for Memory {
@@ -133,6 +386,50 @@ pub fn forward_step(memory: &mut Memory, a: u8) {
     calc_checkdigit5(&mut cpu, memory);
 }
 
+// forward_stepでaを入力するとmemoryになる、入力前のMemory
+// checkdigit5[3]のrorで捨てたbitだけが分からないので、候補は2つまで
+pub fn inverse_step(memory: &Memory, a: u8) -> Vec<Memory> {
+    let mut prev = memory.clone();
+
+    // calc_checkdigit2: checkdigit2は入力後の値で、キャリーもそれで決まる
+    let carry = (memory.checkdigit2[0] >= 0xE5) as u8;
+    prev.checkdigit5[0] = memory.checkdigit5[0].wrapping_sub(a).wrapping_sub(carry);
+    let carry = (prev.checkdigit5[0] as u16 + a as u16 + carry as u16 > 0xFF) as u8;
+    prev.checkdigit5[1] = memory.checkdigit5[1]
+        .wrapping_sub(memory.checkdigit2[1])
+        .wrapping_sub(carry);
+    let carry =
+        (prev.checkdigit5[1] as u16 + memory.checkdigit2[1] as u16 + carry as u16 > 0xFF) as u8;
+
+    // calc_checkdigit3
+    prev.checkdigit5[2] = memory.checkdigit5[2] ^ a;
+
+    // calc_checkdigit1: 入力のbitとxorの前の最上位bitが一致するので、xorしたかどうかが分かる
+    for i in 0..8 {
+        let b = a >> i & 1;
+        let c = (prev.checkdigit2[0] >> 7 != b) as u8;
+        let r0 = prev.checkdigit2[0] ^ if c == 1 { 0x84 } else { 0 };
+        let r1 = prev.checkdigit2[1] ^ if c == 1 { 0x08 } else { 0 };
+        prev.checkdigit2 = [r0 << 1 | r1 >> 7, r1 << 1 | c];
+    }
+
+    // calc_checkdigit4, calc_checkdigit5: rorで捨てたbit(キャリー)ごとに試す
+    (0..2u8)
+        .filter_map(|c| {
+            let v = memory.checkdigit5[3].wrapping_sub(a).wrapping_sub(c);
+            if v >> 7 != carry {
+                return None;
+            }
+            let carry = (v as u16 + a as u16 + c as u16 > 0xFF) as u8;
+            let mut prev = prev.clone();
+            prev.checkdigit5[3] = v << 1 | c;
+            prev.checkdigit5[4] =
+                memory.checkdigit5[4].checked_sub(carry + a.count_ones() as u8)?;
+            Some(prev)
+        })
+        .collect()
+}
+
 pub fn forward_word(memory: &mut Memory, word: &[usize]) {
     word.iter()
         .map(|&c| CHAR_CODES[c])
@@ -150,3 +447,64 @@ pub fn satisfy(password: &[usize], expected_memory: &Memory) -> bool {
 
     expected_memory == &memory
 }
+
+#[test]
+fn inverse_step_undoes_forward_step() {
+    use crate::rng::SplitMix64;
+
+    let mut rng = SplitMix64::new(0);
+    for _ in 0..1000 {
+        let len = 1 + rng.below(MAX_PASSWORD_LEN as u64) as usize;
+        let password: Vec<_> = (0..len)
+            .map(|_| rng.below(CHAR_CODES.len() as u64) as usize)
+            .collect();
+        let mut memory = Memory::new(len as u8);
+        forward_word(&mut memory, &password);
+
+        // 後ろから1文字ずつ戻すと、候補のどれかが初期値になる。どの候補も進めると元に戻る
+        let mut candidates = vec![memory];
+        for &c in password.iter().rev() {
+            let mut prev = Vec::new();
+            for m in &candidates {
+                for p in inverse_step(m, CHAR_CODES[c]) {
+                    let mut next = p.clone();
+                    forward_step(&mut next, CHAR_CODES[c]);
+                    assert!(next == *m);
+                    prev.push(p);
+                }
+            }
+            candidates = prev;
+        }
+        assert!(
+            candidates.contains(&Memory::new(len as u8)),
+            "{}",
+            to_string(&password)
+        );
+    }
+}
+
+#[test]
+fn validate_checks_consistency_with_length() {
+    use crate::rng::SplitMix64;
+
+    // 本物のハッシュ値は弾かない
+    let mut rng = SplitMix64::new(1);
+    for _ in 0..200 {
+        let len = 1 + rng.below(MAX_PASSWORD_LEN as u64) as usize;
+        let password: Vec<_> = (0..len)
+            .map(|_| rng.below(CHAR_CODES.len() as u64) as usize)
+            .collect();
+        let mut memory = Memory::new(len as u8);
+        forward_word(&mut memory, &password);
+        assert!(memory.validate().is_ok(), "{}", to_string(&password));
+    }
+
+    let validate = |s: &str| s.parse::<Memory>().unwrap().validate();
+    assert!(validate("00 51 03 3A E9 08 23 07").is_ok());
+    // 短いパスワードは全部試して確かめる
+    assert!(validate("00 51 03 3A E9 08 23 06").is_err());
+    // xorが文字コードの範囲を超えている
+    assert!(validate("12 34 0C 56 78 9A BC 20").is_err());
+    // 4文字では和がここまで大きくならない
+    assert!(validate("12 34 04 F0 78 1A BC 08").is_err());
+}
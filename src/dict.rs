@@ -1,320 +1,1649 @@
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
-use crate::bitset::BitSet256;
-use crate::cpu::{forward_word, Memory};
+use crate::composition::{Composition, Limits};
+use crate::cpu::{forward_word, Memory, PartialMemory};
 
 use crate::domain::{to_string, CHAR_CODES, CODE2CHAR};
-use crate::opt::OPT;
-use crate::pruning::{is_valid_password, satisfy_option_constraint};
+use crate::lm::{LmPruning, NgramModel};
+use crate::mangle::{mangle, parse_mangle_rules, Origin};
+use crate::opt::{BestOpt, DeepenOpt, MergeOpt, SearchOpt, Shard, OPT};
+use crate::pattern::{
+    build_pattern1, build_pattern2, build_reachable, Pattern1, Pattern2, Reachable, Transitions,
+};
+use crate::progress::{report_progress, Stats, StatsTable};
+use crate::pruning::{States, Validators};
+use crate::target::load_registry;
 
-#[derive(Hash)]
-struct Dict {
-    words: Vec<Vec<usize>>,
+pub struct Dict {
+    pub words: Vec<Vec<usize>>,
+    // wordsと同じ順の、辞書に書かれた重みとタグ
+    pub info: Vec<WordInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WordInfo {
+    pub weight: f64,
+    // name, place, number, game-termなど
+    pub tags: Vec<String>,
+    // --mangleのルールで書き換えて足した単語なら、その元
+    pub origin: Option<Origin>,
+}
+
+// キャッシュやクラスタの照合に使うので、探索に関わる単語の並びだけをハッシュする
+impl Hash for Dict {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.words.hash(state);
+    }
 }
 
 impl Dict {
-    fn new() -> Self {
-        let dict_file = "./dict.txt";
-        let mut file = std::fs::File::open(dict_file).unwrap();
+    pub fn new() -> Self {
+        Self::load(Path::new("./dict.txt"))
+    }
+
+    // 辞書ファイルを読み、--mangleがあれば書き換えた単語を足す
+    pub fn load(dict_file: &Path) -> Self {
+        let mut file = std::fs::File::open(dict_file)
+            .unwrap_or_else(|e| panic!("cannot read {}: {}", dict_file.display(), e));
         let mut s = String::new();
         file.read_to_string(&mut s).unwrap();
 
-        let mut set = HashSet::new();
         let mut words = Vec::new();
-        for s in s.split_whitespace() {
-            if s.is_empty() || s.contains(';') || !set.insert(s) {
-                continue;
-            }
+        let mut info = Vec::new();
+        for entry in parse_dict(&s).unwrap_or_else(|e| panic!("{}:{}", dict_file.display(), e)) {
             let mut v = Vec::new();
-            for c in s.chars() {
+            for c in entry.word.chars() {
                 v.push({
                     let i = CODE2CHAR.iter().position(|&c2| c == c2).unwrap();
                     CHAR_CODES.iter().position(|&j| i == j as usize).unwrap()
                 });
             }
             words.push(v);
+            info.push(WordInfo {
+                weight: entry.weight,
+                tags: entry.tags.into_iter().map(str::to_string).collect(),
+                origin: None,
+            });
         }
 
-        Dict { words }
-    }
-}
-
-pub fn dict_search(expected_memory: &Memory) {
-    let dict = Dict::new();
-
-    fn build_pattern1(dict: &Dict, expected_memory: &Memory) -> Vec<Vec<Vec<Vec<bool>>>> {
-        eprintln!("calc DP1");
-
-        let bit = expected_memory.bit();
-        let len = expected_memory.len();
-        let sum = expected_memory.sum();
-        let xor = expected_memory.xor();
-
-        let mut pattern = vec![vec![vec![vec![false; 0x100]; 0x100]; bit + 1]; len + 1];
-
-        pattern[len][bit][sum][xor] = true;
-
-        for len in (0..pattern.len()).rev() {
-            for bit in 0..pattern[len].len() {
-                for sum in 0..0x100 {
-                    for xor in 0..0x100 {
-                        if !pattern[len][bit][sum][xor] {
-                            continue;
-                        }
-
-                        for word in &dict.words {
-                            if len < word.len() {
-                                continue;
-                            }
-
-                            let len = len - word.len();
-                            if !satisfy_option_constraint(expected_memory, len, word) {
-                                continue;
-                            }
-
-                            let mut bit = bit;
-                            let mut sum = sum;
-                            let mut xor = xor;
-
-                            let mut dbit = 0;
-                            for &i in word {
-                                let c = CHAR_CODES[i] as usize;
-                                dbit += c.count_ones() as usize;
-                                sum = (sum - c) & 0xFF;
-                                xor ^= c;
-                            }
-                            if bit < dbit {
-                                continue;
-                            }
-                            bit -= dbit;
-                            pattern[len][bit][sum][xor] = true;
-                            if bit > 1 {
-                                pattern[len][bit - 1][sum][xor] = true;
-                                pattern[len][bit - 1][(sum - 1) & 0xFF][xor] = true;
-                            }
-                            pattern[len][bit][(sum - 1) & 0xFF][xor] = true;
-                        }
-                    }
+        let mut dict = Dict { words, info };
+        if let Some(path) = &OPT.mangle {
+            let s = std::fs::read_to_string(path)
+                .unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e));
+            let rules =
+                parse_mangle_rules(&s).unwrap_or_else(|e| panic!("{}:{}", path.display(), e));
+            let added = mangle(&mut dict, &rules);
+            eprintln!("added {} words with {} mangling rules", added, rules.len());
+            // 結果のr3などがどのルールか分かるように
+            for rule in &rules {
+                eprintln!("  {}\t{}", rule.name, rule.text);
+            }
+        }
+        dict
+    }
+
+    // もっともらしさに足す、単語の重みのlog10
+    pub fn log_weight(&self, w: usize) -> f64 {
+        self.info[w].weight.log10()
+    }
+
+    // segmentationで区切ったパスワードの単語を、describeで並べたもの。HENTAI1=HENTAI+r1 OSUGI
+    pub fn describe_segmentation(&self, password: &[usize], segmentation: &[usize]) -> String {
+        let mut start = 0;
+        let mut words = Vec::new();
+        for &len in segmentation {
+            let piece = &password[start..start + len];
+            start += len;
+            words.push(match self.words.iter().position(|w| w == piece) {
+                Some(w) => self.describe(w),
+                None => to_string(piece),
+            });
+        }
+        words.join(" ")
+    }
+
+    // 元の単語とルール、タグを付けた表示。KAPPA1=KAPPA+r3#name#game-term
+    pub fn describe(&self, w: usize) -> String {
+        let mut s = to_string(&self.words[w]);
+        if let Some(origin) = &self.info[w].origin {
+            s += &format!("={}+{}", to_string(&self.words[origin.base]), origin.rule);
+        }
+        for tag in &self.info[w].tags {
+            s.push('#');
+            s.push_str(tag);
+        }
+        s
+    }
+}
+
+#[derive(Debug)]
+pub struct DictEntry<'a> {
+    pub word: &'a str,
+    pub weight: f64,
+    pub tags: Vec<&'a str>,
+}
+
+// 辞書ファイルの単語を、重複を除いて出現順に返す
+//   KAPPA w=2.5 #name #game-term ; コメント
+// 1行に単語をいくつ並べてもよく、単語のあとのw=とタグはその単語に付く。重みの省略時は1
+// ;から行末まではコメント
+pub fn parse_dict(s: &str) -> Result<Vec<DictEntry<'_>>, String> {
+    let mut entries: Vec<DictEntry> = Vec::new();
+    let mut set = HashSet::new();
+    for (line_no, line) in s.lines().enumerate() {
+        let line = line.split(';').next().unwrap();
+        let error = |msg: String| format!("{}: {}", line_no + 1, msg);
+        // この行で最後に読んだ単語。重複して読み飛ばしたものはNone
+        let mut last: Option<Option<usize>> = None;
+        for token in line.split_whitespace() {
+            let meta = token.starts_with('#') || token.starts_with("w=");
+            if !meta {
+                last = Some(set.insert(token).then(|| {
+                    entries.push(DictEntry {
+                        word: token,
+                        weight: 1.0,
+                        tags: Vec::new(),
+                    });
+                    entries.len() - 1
+                }));
+                continue;
+            }
+
+            let entry = match last {
+                Some(Some(i)) => &mut entries[i],
+                Some(None) => continue,
+                None => return Err(error(format!("{} must follow a word", token))),
+            };
+            if let Some(tag) = token.strip_prefix('#') {
+                entry.tags.push(tag);
+            } else {
+                let weight: f64 = token[2..]
+                    .parse()
+                    .map_err(|e| error(format!("{}: {}", token, e)))?;
+                if !(weight.is_finite() && weight > 0.0) {
+                    return Err(error(format!("weight must be positive: {}", token)));
                 }
+                entry.weight = weight;
             }
         }
+    }
+    Ok(entries)
+}
 
-        pattern
+pub fn parse_dict_words(s: &str) -> Result<Vec<&str>, String> {
+    Ok(parse_dict(s)?.into_iter().map(|e| e.word).collect())
+}
+
+pub struct SearchResult {
+    pub passwords: Vec<Vec<usize>>,
+    // 近い順に並べたもの
+    pub near_misses: Vec<NearMiss>,
+    pub nodes: u64,
+    pub elapsed: Duration,
+    // 長さごとの枝刈りの統計。同時に探索したターゲットで共通
+    pub stats: StatsTable,
+    // 言語モデルがあれば、passwordsと同じ順のlog10確率。passwordsはもっともらしい順に並ぶ
+    pub scores: Vec<f64>,
+}
+
+// ハッシュ値が何バイトか違うだけのパスワード
+pub struct NearMiss {
+    pub password: Vec<usize>,
+    pub memory: Memory,
+}
+
+// 長さ以外の分かっているバイトのうち、一致した数と全体の数
+fn matched_bytes(target: &PartialMemory, memory: &Memory) -> (usize, usize) {
+    let mut matched = 0;
+    let mut known = 0;
+    for (i, (t, b)) in target.bytes.iter().zip(memory.to_bytes()).enumerate() {
+        if let (Some(t), true) = (t, i != 2) {
+            known += 1;
+            if *t == b {
+                matched += 1;
+            }
+        }
     }
+    (matched, known)
+}
 
-    fn build_pattern2(dict: &Dict, expected_memory: &Memory) -> Vec<Vec<Vec<Vec<bool>>>> {
-        eprintln!("calc DP2");
+// checkdigit2, sum, xor, bitの順に、一致したフィールド
+fn matched_fields(target: &PartialMemory, memory: &Memory) -> Vec<&'static str> {
+    let bytes = memory.to_bytes();
+    let eq = |i: usize| target.bytes[i].is_none_or(|b| b == bytes[i]);
+    [
+        ("checkdigit2", eq(0) && eq(1)),
+        ("sum", eq(3)),
+        ("xor", eq(5)),
+        ("bit", eq(7)),
+    ]
+    .into_iter()
+    .filter(|&(_, m)| m)
+    .map(|(name, _)| name)
+    .collect()
+}
 
-        std::fs::create_dir_all("cache").unwrap();
-        let mut hasher = DefaultHasher::new();
-        dict.hash(&mut hasher);
-        OPT.prefix.hash(&mut hasher);
-        OPT.suffix.hash(&mut hasher);
-        let hash = hasher.finish();
-        let cache_path = format!("cache/pattern2_{}.bin", hash);
+// 一致したバイト数が多いものほど、同じなら前のフィールドが一致しているものほど近い
+fn near_miss_rank(target: &PartialMemory, memory: &Memory) -> (usize, usize) {
+    let fields = matched_fields(target, memory);
+    let flags = ["checkdigit2", "sum", "xor", "bit"]
+        .iter()
+        .fold(0, |acc, f| acc << 1 | fields.contains(f) as usize);
+    (matched_bytes(target, memory).0, flags)
+}
 
-        if !OPT.ignore_cache {
-            if let Ok(mut f) = std::fs::File::open(&cache_path) {
-                let mut pattern = Vec::new();
-                f.read_to_end(&mut pattern).unwrap();
-                return bincode::deserialize(&pattern[..]).unwrap();
+pub fn describe_near_miss(target: &PartialMemory, near_miss: &NearMiss) -> String {
+    let (matched, known) = matched_bytes(target, &near_miss.memory);
+    format!(
+        "{}\t{}\t{}/{}\t{}",
+        to_string(&near_miss.password),
+        near_miss.memory.to_hex(),
+        matched,
+        known,
+        matched_fields(target, &near_miss.memory).join(",")
+    )
+}
+
+// 分かっているバイトのうちk個以下を??にしたもの。長さのバイトはそのまま
+fn near_miss_variants(target: &PartialMemory, k: usize) -> Vec<PartialMemory> {
+    let known: Vec<_> = (0..8)
+        .filter(|&i| i != 2 && target.bytes[i].is_some())
+        .collect();
+    (0..1usize << known.len())
+        .filter(|mask| mask.count_ones() as usize <= k)
+        .map(|mask| {
+            let mut variant = target.clone();
+            for (j, &i) in known.iter().enumerate() {
+                if mask >> j & 1 == 1 {
+                    variant.bytes[i] = None;
+                }
             }
+            variant
+        })
+        .collect()
+}
+
+// 表が参照するバイトだけを残す。残りが同じなら表も同じなので使い回せる
+fn keep_bytes(target: &PartialMemory, indices: &[usize]) -> PartialMemory {
+    let mut target = target.clone();
+    for (i, b) in target.bytes.iter_mut().enumerate() {
+        if !indices.contains(&i) {
+            *b = None;
         }
+    }
+    target
+}
 
-        let len = expected_memory.len();
+// 辞書と、ターゲットによらない表をまとめて持っておき、複数のターゲットの探索で使い回す
+pub struct Searcher {
+    pub dict: Dict,
+    pub transitions: Transitions,
+    reachable: HashMap<usize, Arc<Reachable>>,
+    // 0より大きければ、この数以下のバイトが違うパスワードも報告する
+    pub near_miss: usize,
+    // 指定されていれば、その担当分だけを探索する
+    pub shard: Option<Shard>,
+    // 進捗を表示する間隔
+    pub progress: Option<Duration>,
+    // 枝刈りの条件。registerで独自のバリデータを足せる
+    pub validators: Validators,
+    // 見つかったパスワードを並べる言語モデル
+    pub lm: Option<Arc<NgramModel>>,
+    // 報告済みのターゲットとパスワード。探索し直しても、解にも近い候補にも出さない
+    pub reported: HashSet<(PartialMemory, Vec<usize>)>,
+}
 
-        let mut dp = vec![vec![vec![BitSet256::default(); 0x100]; 0x100]; len + 1];
+// 探索の前に作っておく、ターゲットごとの表
+pub struct Prepared {
+    targets: Vec<TargetTables>,
+    len: usize,
+}
+
+struct TargetTables {
+    expected_memory: PartialMemory,
+    // near-missでは何バイトかを??にした表も持ち、どれかが通せば探索を続ける
+    tables: Vec<(Arc<Pattern1>, Arc<Pattern2>)>,
+    max_bit: usize,
+}
+
+// 同じ長さのターゲットをまとめて探索する
+// まだ解の可能性が残っているターゲットの集合をbitで持ち、どれか1つでも残っていれば探索を続ける
+struct Context<'a> {
+    dict: &'a Dict,
+    // 枝刈りは長さしか見ないので、ターゲットの代わりに渡す
+    dummy: Memory,
+    targets: &'a [TargetTables],
+    near_miss: usize,
+    found: Mutex<Vec<(usize, Vec<usize>)>>,
+    // 表示したターゲットとパスワード。区切り方が違うだけの同じパスワードは1回だけ表示する
+    printed: Mutex<HashSet<(usize, Vec<usize>)>>,
+    near_misses: Mutex<Vec<(usize, NearMiss)>>,
+    validators: &'a Validators,
+    reported: &'a HashSet<(PartialMemory, Vec<usize>)>,
+    stats: Stats,
+}
+
+impl Context<'_> {
+    fn len(&self) -> usize {
+        self.dummy.len()
+    }
+
+    fn targets(&self, live: u64) -> impl Iterator<Item = (usize, &TargetTables)> {
+        self.targets
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| live >> i & 1 == 1)
+    }
+}
+
+// 単語を足したあとのハッシュ値と、バリデータの状態
+// 足せれば、passwordに単語を、segmentationに単語の長さを足す
+#[allow(clippy::too_many_arguments)]
+fn next(
+    ctx: &Context,
+    append_word: &[usize],
+    max_bit: usize,
+    memory: &Memory,
+    password: &mut Vec<usize>,
+    segmentation: &mut Vec<usize>,
+    states: &States,
+    stats: Option<&Stats>,
+) -> Option<(Memory, States)> {
+    // 枝刈りは長さしか見ないので、ターゲットの代わりに渡す
+    let expected_memory = &ctx.dummy;
+    if password.len() + append_word.len() > expected_memory.len() {
+        return None;
+    }
+
+    let states =
+        match ctx
+            .validators
+            .append(expected_memory, password, segmentation, states, append_word)
+        {
+            Ok(states) => states,
+            Err(i) => {
+                if let Some(stats) = stats {
+                    stats.rejects[i][password.len()].fetch_add(1, Ordering::Relaxed);
+                }
+                return None;
+            }
+        };
+
+    let mut memory = memory.clone();
+    forward_word(&mut memory, append_word);
+
+    if memory.bit() > max_bit {
+        return None;
+    }
+
+    password.extend(append_word);
+    segmentation.push(append_word.len());
+
+    Some((memory, states))
+}
+
+// DP1, DP2の表でまだ解がありうるターゲット。どれもなければ、打ち切った表を統計に数える
+fn live_targets(
+    ctx: &Context,
+    memory: &Memory,
+    len: usize,
+    live: u64,
+    stats: Option<&Stats>,
+) -> u64 {
+    let bit = memory.bit();
+    let sum = memory.sum();
+    let xor = memory.xor();
+    let s0 = memory.checkdigit2[0] as usize;
+    let s1 = memory.checkdigit2[1] as usize;
+    let s2 = memory.checkdigit5[0] as usize;
+
+    let mut next_live = 0;
+    let mut pattern1_ok = false;
+    for (i, t) in ctx.targets(live) {
+        let alive = t.tables.iter().any(|(pattern1, pattern2)| {
+            let ok = pattern1[len].get(bit).is_some_and(|p| p[sum][xor]);
+            pattern1_ok |= ok;
+            ok && pattern2[len][s0][s1].get(s2)
+        });
+        if alive {
+            next_live |= 1 << i;
+        }
+    }
+    if next_live == 0 {
+        if let Some(stats) = stats {
+            let cuts = if pattern1_ok {
+                &stats.pattern2_cuts
+            } else {
+                &stats.pattern1_cuts
+            };
+            cuts[len].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    next_live
+}
 
+// 長さの揃ったパスワードを、一致したターゲットの解と近い候補に振り分ける。一致したターゲットの番号を返す
+fn check_leaf(ctx: &Context, memory: &Memory, password: &[usize], live: u64) -> Vec<usize> {
+    let mut matched_targets = Vec::new();
+    for (i, t) in ctx.targets(live) {
+        if !ctx.reported.is_empty()
+            && ctx
+                .reported
+                .contains(&(t.expected_memory.clone(), password.to_vec()))
         {
-            let s0 = expected_memory.checkdigit2[0] as usize;
-            let s1 = expected_memory.checkdigit2[1] as usize;
-            let s2 = expected_memory.checkdigit5[0] as usize;
-            dp[len][s0][s1].flip(s2);
-        }
-
-        // by dict
-        // グラフを作って最外ループを無くしたいが、自分の環境だとメモリが足りないため断念
-        loop {
-            eprint!(".");
-            let mut updated = false;
-
-            let mut visited = vec![vec![vec![BitSet256::default(); 0x100]; 0x100]; len + 1];
-            {
-                let memory = Memory::new(expected_memory.len() as u8);
-                let s0 = memory.checkdigit2[0] as usize;
-                let s1 = memory.checkdigit2[1] as usize;
-                let s2 = memory.checkdigit5[0] as usize;
-                visited[0][s0][s1].flip(s2);
-            }
-
-            for len in 0..visited.len() {
-                for s0 in 0..0x100 {
-                    for s1 in 0..0x100 {
-                        for word in &dict.words {
-                            if len + word.len() >= visited.len() {
-                                continue;
-                            }
-
-                            if !satisfy_option_constraint(expected_memory, len, word) {
-                                continue;
-                            }
-
-                            let mut memory = Memory {
-                                checkdigit2: [s0 as u8, s1 as u8],
-                                password_len: 0,
-                                checkdigit5: [0, 0, 0, 0, 0],
-                            };
-
-                            forward_word(&mut memory, word);
-
-                            let next_len = len + word.len();
-                            let next_s0 = memory.checkdigit2[0] as usize;
-                            let next_s1 = memory.checkdigit2[1] as usize;
-                            let offset = memory.checkdigit5[0] as usize;
-
-                            let rotated = visited[len][s0][s1].rot_left(offset);
-                            visited[next_len][next_s0][next_s1] |= rotated;
-
-                            let rotated = dp[next_len][next_s0][next_s1].rot_right(offset);
-                            let prev = dp[len][s0][s1].clone();
-                            dp[len][s0][s1] |= &rotated & &visited[len][s0][s1];
-                            updated |= prev != dp[len][s0][s1];
-
-                            // for s2 in 0..0x100 {
-                            //     if !visited[len][s0][s1][s2] {
-                            //         continue;
-                            //     }
-
-                            //     let next_s2 = (s2 + memory.checkdigit5[0] as usize) & 0xFF;
-                            //     visited[next_len][next_s0][next_s1][next_s2] = true;
-
-                            //     if !dp[len][s0][s1][s2] && dp[next_len][next_s0][next_s1][next_s2] {
-                            //         dp[len][s0][s1][s2] = true;
-                            //         updated = true;
-                            //     }
-                            // }
-                        }
+            continue;
+        }
+        if t.expected_memory.matches(memory) {
+            ctx.found.lock().unwrap().push((i, password.to_vec()));
+            ctx.stats.found.fetch_add(1, Ordering::Relaxed);
+            matched_targets.push(i);
+            continue;
+        }
+
+        let (matched, known) = matched_bytes(&t.expected_memory, memory);
+        if known - matched <= ctx.near_miss {
+            let near_miss = NearMiss {
+                password: password.to_vec(),
+                memory: memory.clone(),
+            };
+            ctx.near_misses.lock().unwrap().push((i, near_miss));
+        }
+    }
+    matched_targets
+}
+
+// forcedは探索を分割したときに、この先で選ぶ単語として決まっているもの
+// forcedの先頭shared個は前の単位と同じ選び方で、そこまでのノードは前の単位で統計に数えている
+#[allow(clippy::too_many_arguments)]
+fn dfs_dict(
+    ctx: &Context,
+    memory: &Memory,
+    password: &[usize],
+    segmentation: &[usize],
+    states: &States,
+    live: u64,
+    forced: &[usize],
+    shared: usize,
+) {
+    let len = password.len();
+    let stats = (shared == 0).then_some(&ctx.stats);
+    if let Some(stats) = stats {
+        stats.nodes[len].fetch_add(1, Ordering::Relaxed);
+    }
+
+    let live = live_targets(ctx, memory, len, live, stats);
+    if live == 0 {
+        return;
+    }
+
+    if OPT.verbose {
+        eprintln!(
+            "checking: {}",
+            password
+                .iter()
+                .map(|&p| CODE2CHAR[CHAR_CODES[p] as usize])
+                .collect::<String>()
+        );
+    }
+
+    if len == ctx.len() {
+        // 決まっている単語をまだ使い切っていなければ、別の単位の担当
+        if forced.first().is_some_and(|&w| w != END) {
+            return;
+        }
+
+        for i in check_leaf(ctx, memory, password, live) {
+            if !ctx.printed.lock().unwrap().insert((i, password.to_vec())) {
+                continue;
+            }
+            let words = ctx.dict.describe_segmentation(password, segmentation);
+            if ctx.targets.len() == 1 {
+                println!("find: {:?}, {}\t{}", &password, to_string(password), words);
+            } else {
+                println!(
+                    "find: {:?}, {}\t{} ({})",
+                    &password,
+                    to_string(password),
+                    words,
+                    ctx.targets[i].expected_memory.to_hex()
+                );
+            }
+        }
+
+        return;
+    }
+
+    let max_bit = ctx.targets(live).map(|(_, t)| t.max_bit).max().unwrap();
+    match forced.split_first() {
+        Some((&END, _)) => {}
+        Some((&w, rest)) => {
+            let mut password = password.to_vec();
+            let mut segmentation = segmentation.to_vec();
+            let word = &ctx.dict.words[w];
+            // 足した先のノードが前の単位と違えば、弾いた数はこの単位で数える
+            let stats = (shared <= 1).then_some(&ctx.stats);
+            if let Some((memory, states)) = next(
+                ctx,
+                word,
+                max_bit,
+                memory,
+                &mut password,
+                &mut segmentation,
+                states,
+                stats,
+            ) {
+                dfs_dict(
+                    ctx,
+                    &memory,
+                    &password,
+                    &segmentation,
+                    &states,
+                    live,
+                    rest,
+                    shared.saturating_sub(1),
+                );
+            }
+        }
+        None => ctx.dict.words.par_iter().for_each(|word| {
+            let mut password = password.to_vec();
+            let mut segmentation = segmentation.to_vec();
+            if let Some((memory, states)) = next(
+                ctx,
+                word,
+                max_bit,
+                memory,
+                &mut password,
+                &mut segmentation,
+                states,
+                stats,
+            ) {
+                dfs_dict(
+                    ctx,
+                    &memory,
+                    &password,
+                    &segmentation,
+                    &states,
+                    live,
+                    &[],
+                    0,
+                );
+            }
+        }),
+    }
+}
+
+// 組み込みの条件に、単語の組み立て方の制約と言語モデルの枝刈りを足したもの
+pub fn build_validators(dict: &Dict, lm: Option<&Arc<NgramModel>>, limits: Limits) -> Validators {
+    let mut validators = Validators::new();
+    if let Some(composition) = Composition::new(dict, limits) {
+        validators.register(Box::new(composition));
+    }
+    if let (Some(model), Some(threshold)) = (lm, OPT.lm_threshold) {
+        validators.register(Box::new(LmPruning {
+            model: model.clone(),
+            threshold,
+        }));
+    }
+    validators
+}
+
+// 探索を分割する単位は、先頭の単語(足りなければ2番目の単語まで)の選び方
+// 単語の番号の並びで表し、ENDはそこでパスワードが終わることを表す
+pub const END: usize = usize::MAX;
+
+pub fn work_units(dict: &Dict, depth: usize) -> Vec<Vec<usize>> {
+    let mut units = vec![vec![]];
+    for _ in 0..depth {
+        let mut next = Vec::new();
+        for unit in units {
+            if unit.last() == Some(&END) {
+                next.push(unit);
+                continue;
+            }
+            if !unit.is_empty() {
+                next.push([&unit[..], &[END]].concat());
+            }
+            for w in 0..dict.words.len() {
+                next.push([&unit[..], &[w]].concat());
+            }
+        }
+        units = next;
+    }
+    units
+}
+
+// --shard i/nで担当する単位。番号をnで割った余りがiのもの
+pub fn shard_units(dict: &Dict, shard: Shard) -> Vec<Vec<usize>> {
+    let depth = if shard.count <= dict.words.len() {
+        1
+    } else {
+        2
+    };
+    work_units(dict, depth)
+        .into_iter()
+        .enumerate()
+        .filter(|(u, _)| u % shard.count == shard.index)
+        .map(|(_, unit)| unit)
+        .collect()
+}
+
+impl Searcher {
+    pub fn new() -> Self {
+        let dict = Dict::new();
+        let transitions = Transitions::new(&dict);
+        let lm = NgramModel::from_opt();
+        let validators = build_validators(&dict, lm.as_ref(), Limits::from_opt());
+        Self {
+            dict,
+            transitions,
+            reachable: HashMap::new(),
+            near_miss: 0,
+            shard: None,
+            progress: None,
+            validators,
+            lm,
+            reported: HashSet::new(),
+        }
+    }
+
+    // 1文字の単語をk個まで許す探索を、k = 0, 1, ..., max_fillersの順に行う
+    // 表は最初に作って使い回し、前の回までに報告したパスワードは報告しない。回ごとにon_roundを呼ぶ
+    // 回ごとに差し替えるのはcompositionのバリデータだけで、registerで足したものはそのまま使う
+    pub fn deepen(
+        &mut self,
+        expected_memories: &[PartialMemory],
+        max_fillers: usize,
+        mut on_round: impl FnMut(usize, &[SearchResult]),
+    ) {
+        let prepared = self.prepare(expected_memories);
+        let units = work_units(&self.dict, 2);
+        let limits = Limits::from_opt();
+        let max_fillers = max_fillers.min(limits.max_singles.unwrap_or(usize::MAX));
+        let mut original = None;
+        for fillers in 0..=max_fillers {
+            let round = Limits {
+                max_singles: Some(fillers),
+                ..limits.clone()
+            };
+            let composition = Composition::new(&self.dict, round).unwrap();
+            let replaced = self.validators.replace(Box::new(composition));
+            if fillers == 0 {
+                original = replaced;
+            }
+            let mut results = self.run_units(&prepared, &units);
+            for (target, result) in expected_memories.iter().zip(&mut results) {
+                // 区切り方が違うだけで同じ回に何度も見つかったものも1つにする
+                let passwords = std::mem::take(&mut result.passwords);
+                let scores = std::mem::take(&mut result.scores);
+                for (i, password) in passwords.into_iter().enumerate() {
+                    if self.reported.insert((target.clone(), password.clone())) {
+                        result.passwords.push(password);
+                        result.scores.extend(scores.get(i));
                     }
                 }
+                for near_miss in &result.near_misses {
+                    self.reported
+                        .insert((target.clone(), near_miss.password.clone()));
+                }
             }
-            if !updated {
-                let dp: Vec<_> = dp
-                    .into_iter()
-                    .map(|dp| {
-                        dp.into_iter()
-                            .map(|dp| dp.into_iter().map(|dp| dp.to_vec()).collect())
-                            .collect()
-                    })
-                    .collect();
+            on_round(fillers, &results);
+        }
+        match original {
+            Some(composition) => {
+                self.validators.replace(composition);
+            }
+            None => {
+                self.validators.remove("composition");
+            }
+        }
+    }
+
+    pub fn reachable(&mut self, len: usize) -> Arc<Reachable> {
+        let Self {
+            dict,
+            transitions,
+            reachable,
+            ..
+        } = self;
+        reachable
+            .entry(len)
+            .or_insert_with(|| Arc::new(build_reachable(dict, transitions, len)))
+            .clone()
+    }
+
+    // 同じ長さのターゲットを1回の探索でまとめて調べる
+    // 結果はターゲットと同じ順に返す。nodes, elapsedは全ターゲットで共通
+    pub fn search_many(&mut self, expected_memories: &[PartialMemory]) -> Vec<SearchResult> {
+        let units = match self.shard {
+            Some(shard) => shard_units(&self.dict, shard),
+            // 先頭の単語だけだと並列に進んでなかなか終わらないので、進捗の見積もりのために細かく分ける
+            None => work_units(&self.dict, 2),
+        };
+        self.search_units(expected_memories, &units)
+    }
+
+    // 探索をunitsの分だけに絞る
+    pub fn search_units(
+        &mut self,
+        expected_memories: &[PartialMemory],
+        units: &[Vec<usize>],
+    ) -> Vec<SearchResult> {
+        let start = Instant::now();
+        let prepared = self.prepare(expected_memories);
+        eprintln!("start search");
+        let mut results = self.run_units(&prepared, units);
+        for result in &mut results {
+            result.elapsed = start.elapsed();
+        }
+        results
+    }
+
+    // ターゲットごとの表を作る。同じターゲットで何度も探索するなら使い回せる
+    pub fn prepare(&mut self, expected_memories: &[PartialMemory]) -> Prepared {
+        assert!(!expected_memories.is_empty() && expected_memories.len() <= 64);
+        let len = expected_memories[0].len();
+        assert!(
+            expected_memories.iter().all(|m| m.len() == len),
+            "targets must have the same length"
+        );
+
+        let reachable = self.reachable(len);
+        let mut pattern1s = HashMap::new();
+        let mut pattern2s = HashMap::new();
+        let targets: Vec<_> = expected_memories
+            .iter()
+            .map(|expected_memory| {
+                let variants = near_miss_variants(expected_memory, self.near_miss);
+                let mut tables = Vec::new();
+                for variant in &variants {
+                    // DP1は長さ, sum, xor, bit、DP2は長さ, checkdigit2, sumしか見ない
+                    let pattern1 = pattern1s
+                        .entry(keep_bytes(variant, &[2, 3, 5, 7]))
+                        .or_insert_with_key(|key| Arc::new(build_pattern1(&self.dict, key)))
+                        .clone();
+                    let pattern2 = pattern2s
+                        .entry(keep_bytes(variant, &[0, 1, 2, 3]))
+                        .or_insert_with_key(|key| {
+                            Arc::new(build_pattern2(
+                                &self.dict,
+                                &self.transitions,
+                                &reachable,
+                                key,
+                            ))
+                        })
+                        .clone();
+                    if !tables
+                        .iter()
+                        .any(|(p1, p2)| Arc::ptr_eq(p1, &pattern1) && Arc::ptr_eq(p2, &pattern2))
+                    {
+                        tables.push((pattern1, pattern2));
+                    }
+                }
+                TargetTables {
+                    expected_memory: expected_memory.clone(),
+                    tables,
+                    max_bit: variants.iter().map(|v| v.max_bit()).max().unwrap(),
+                }
+            })
+            .collect();
+
+        Prepared { targets, len }
+    }
 
-                std::fs::write(&cache_path, bincode::serialize(&dp).unwrap()).unwrap();
-                eprintln!();
-                break dp;
+    pub fn run_units(&self, prepared: &Prepared, units: &[Vec<usize>]) -> Vec<SearchResult> {
+        let start = Instant::now();
+        let ctx = self.context(prepared);
+        let memory = ctx.dummy.clone();
+        let live = u64::MAX >> (64 - prepared.targets.len());
+        let states = self.validators.initial_states();
+
+        // 単位どうしで共通する先頭の部分は、最初の単位でだけ統計に数える
+        let mut seen = HashSet::new();
+        let shared: Vec<usize> = units
+            .iter()
+            .map(|unit| {
+                (0..=unit.len())
+                    .filter(|&k| seen.insert(unit[..k].to_vec()))
+                    .min()
+                    .unwrap_or(unit.len() + 1)
+            })
+            .collect();
+        std::thread::scope(|scope| {
+            if let Some(interval) = self.progress {
+                let stats = &ctx.stats;
+                scope.spawn(move || report_progress(stats, units.len(), start, interval));
+            }
+            units
+                .par_iter()
+                .zip(shared.par_iter())
+                .for_each(|(unit, &shared)| {
+                    dfs_dict(&ctx, &memory, &[], &[], &states, live, unit, shared);
+                    ctx.stats.units_done.fetch_add(1, Ordering::Relaxed);
+                });
+        });
+
+        let mut results = self.collect_results(ctx, start);
+        for result in &mut results {
+            // 区切り方が違うだけの同じパスワードは1つにする
+            result.passwords.sort();
+            result.passwords.dedup();
+            if let Some(lm) = &self.lm {
+                let mut scored: Vec<_> = result
+                    .passwords
+                    .drain(..)
+                    .map(|p| (lm.score(&p), p))
+                    .collect();
+                scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+                (result.scores, result.passwords) = scored.into_iter().unzip();
             }
         }
+        results
     }
 
-    let pattern1 = build_pattern1(&dict, expected_memory);
+    fn context<'a>(&'a self, prepared: &'a Prepared) -> Context<'a> {
+        Context {
+            dict: &self.dict,
+            dummy: Memory::new(prepared.len as u8),
+            targets: &prepared.targets,
+            near_miss: self.near_miss,
+            found: Mutex::new(Vec::new()),
+            printed: Mutex::new(HashSet::new()),
+            near_misses: Mutex::new(Vec::new()),
+            validators: &self.validators,
+            reported: &self.reported,
+            stats: Stats::new(self.validators.names().len()),
+        }
+    }
 
-    let pattern2 = build_pattern2(&dict, expected_memory);
+    // 探索し終えたContextから、ターゲットごとの結果を作る。近い候補は近い順に並べる
+    fn collect_results(&self, ctx: Context, start: Instant) -> Vec<SearchResult> {
+        let stats = ctx.stats.table(ctx.len(), &self.validators.names());
+        let nodes = stats.depths.iter().map(|d| d.nodes).sum();
+        let elapsed = start.elapsed();
+        let mut results: Vec<_> = (0..ctx.targets.len())
+            .map(|_| SearchResult {
+                passwords: Vec::new(),
+                near_misses: Vec::new(),
+                nodes,
+                elapsed,
+                stats: stats.clone(),
+                scores: Vec::new(),
+            })
+            .collect();
+        for (i, password) in ctx.found.into_inner().unwrap() {
+            results[i].passwords.push(password);
+        }
+        for (i, near_miss) in ctx.near_misses.into_inner().unwrap() {
+            results[i].near_misses.push(near_miss);
+        }
+        for (result, target) in results.iter_mut().zip(ctx.targets) {
+            result.near_misses.sort_by_cached_key(|n| {
+                let (matched, flags) = near_miss_rank(&target.expected_memory, &n.memory);
+                (std::cmp::Reverse((matched, flags)), n.password.clone())
+            });
+        }
+        results
+    }
+
+    // 途中までのパスワードのもっともらしさ。言語モデルがあればそのlog10確率、なければ単語の数が少ないほど高い
+    // 辞書に重みがあれば、使った単語の重みのlog10を足す
+    fn plausibility(&self, password: &[usize], words: usize, log_weight: f64) -> f64 {
+        let base = match &self.lm {
+            Some(lm) => lm.score(password),
+            None => -(words as f64),
+        };
+        base + log_weight
+    }
 
-    fn next(
-        append_word: &[usize],
-        expected_memory: &Memory,
-        memory: &Memory,
-        password: &mut Vec<usize>,
-    ) -> Option<Memory> {
-        if password.len() + append_word.len() > expected_memory.len() {
-            return None;
+    // 残りのremain文字を足してももっともらしさがこれより上がらない、という見積もり
+    fn plausibility_bound(&self, remain: usize) -> f64 {
+        let max_word_len = self.dict.words.iter().map(|w| w.len()).max().unwrap();
+        let max_log_weight = (0..self.dict.words.len())
+            .map(|w| self.dict.log_weight(w))
+            .fold(f64::NEG_INFINITY, f64::max);
+        // 1単語で上がる最大。正なら1文字ずつの単語、負なら一番長い単語で埋めるのが最大
+        let gain = match &self.lm {
+            Some(_) => max_log_weight,
+            None => max_log_weight - 1.0,
+        };
+        let words = if gain > 0.0 {
+            gain * remain as f64
+        } else {
+            gain * remain.div_ceil(max_word_len) as f64
+        };
+        match &self.lm {
+            Some(lm) => lm.max_log_prob() * remain as f64 + words,
+            None => words,
         }
+    }
+
+    // もっともらしい途中のパスワードから先に広げる最良優先探索。見つけた順に、単語の並びを付けてon_foundを呼ぶ
+    // キューがbeamを超えたら見込みの一番低いものを捨てるので、捨てなければもっともらしい順に見つかる
+    pub fn best_first(
+        &mut self,
+        expected_memories: &[PartialMemory],
+        beam: usize,
+        limit: Option<usize>,
+        mut on_found: impl FnMut(usize, &[usize], &str, f64),
+    ) -> BestFirstResult {
+        let start = Instant::now();
+        let prepared = self.prepare(expected_memories);
+        eprintln!("start best-first search");
+        let ctx = self.context(&prepared);
+        let len = ctx.len();
 
-        if !is_valid_password(expected_memory, password, append_word) {
-            return None;
+        // 見込み(ここまでのもっともらしさ + 残りの見積もり)、長いもの、後から入れたものの順に取り出す
+        struct Priority(f64);
+        impl PartialEq for Priority {
+            fn eq(&self, other: &Self) -> bool {
+                self.cmp(other).is_eq()
+            }
+        }
+        impl Eq for Priority {}
+        impl PartialOrd for Priority {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for Priority {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                self.0.total_cmp(&other.0)
+            }
+        }
+        struct Node {
+            memory: Memory,
+            password: Vec<usize>,
+            states: States,
+            live: u64,
+            // 使った単語の長さ
+            segmentation: Vec<usize>,
+            log_weight: f64,
+            score: f64,
         }
 
-        let mut memory = memory.clone();
-        forward_word(&mut memory, append_word);
+        // 残りの文字数ごとの見積もり
+        let bounds: Vec<f64> = (0..=len).map(|r| self.plausibility_bound(r)).collect();
+        let mut queue = BTreeMap::new();
+        let mut seq = 0u64;
+        let mut dropped = 0u64;
+        let mut push = |queue: &mut BTreeMap<_, _>, node: Node| {
+            let priority = node.score + bounds[len - node.password.len()];
+            queue.insert((Priority(priority), node.password.len(), seq), node);
+            seq += 1;
+            if queue.len() > beam {
+                queue.pop_first();
+                dropped += 1;
+            }
+        };
 
-        if memory.bit() > expected_memory.bit() {
-            return None;
+        let root = Node {
+            memory: ctx.dummy.clone(),
+            password: Vec::new(),
+            states: self.validators.initial_states(),
+            live: u64::MAX >> (64 - prepared.targets.len()),
+            segmentation: Vec::new(),
+            log_weight: 0.0,
+            score: self.plausibility(&[], 0, 0.0),
+        };
+        ctx.stats.nodes[0].fetch_add(1, Ordering::Relaxed);
+        let live = live_targets(&ctx, &root.memory, 0, root.live, Some(&ctx.stats));
+        if live != 0 {
+            push(&mut queue, Node { live, ..root });
         }
 
-        password.extend(append_word);
+        // 同じパスワードを別の区切り方で見つけても、最初の1回だけ報告する
+        let mut reported = HashSet::new();
+        let mut found = vec![Vec::new(); prepared.targets.len()];
+        while let Some((_, node)) = queue.pop_last() {
+            if node.password.len() == len {
+                for i in check_leaf(&ctx, &node.memory, &node.password, node.live) {
+                    if reported.insert((i, node.password.clone())) {
+                        let words = ctx
+                            .dict
+                            .describe_segmentation(&node.password, &node.segmentation);
+                        on_found(i, &node.password, &words, node.score);
+                        found[i].push((node.password.clone(), node.score));
+                    }
+                }
+                if limit.is_some_and(|limit| reported.len() >= limit) {
+                    break;
+                }
+                continue;
+            }
+
+            let max_bit = ctx
+                .targets(node.live)
+                .map(|(_, t)| t.max_bit)
+                .max()
+                .unwrap();
+            for (w, word) in self.dict.words.iter().enumerate() {
+                let mut password = node.password.clone();
+                let mut segmentation = node.segmentation.clone();
+                let Some((memory, states)) = next(
+                    &ctx,
+                    word,
+                    max_bit,
+                    &node.memory,
+                    &mut password,
+                    &mut segmentation,
+                    &node.states,
+                    Some(&ctx.stats),
+                ) else {
+                    continue;
+                };
+                ctx.stats.nodes[password.len()].fetch_add(1, Ordering::Relaxed);
+                let live = live_targets(&ctx, &memory, password.len(), node.live, Some(&ctx.stats));
+                if live == 0 {
+                    continue;
+                }
+                let log_weight = node.log_weight + self.dict.log_weight(w);
+                let score = self.plausibility(&password, segmentation.len(), log_weight);
+                push(
+                    &mut queue,
+                    Node {
+                        memory,
+                        password,
+                        states,
+                        live,
+                        segmentation,
+                        log_weight,
+                        score,
+                    },
+                );
+            }
+        }
+        let exhausted = queue.is_empty();
 
-        Some(memory)
+        let mut results = self.collect_results(ctx, start);
+        for (result, found) in results.iter_mut().zip(found) {
+            (result.passwords, result.scores) = found.into_iter().unzip();
+        }
+        BestFirstResult {
+            results,
+            dropped,
+            exhausted,
+        }
     }
+}
 
-    fn dfs_dict(
-        dict: &Dict,
-        pattern1: &[Vec<Vec<Vec<bool>>>],
-        pattern2: &[Vec<Vec<Vec<bool>>>],
-        expected_memory: &Memory,
-        memory: &Memory,
-        password: &[usize],
-    ) {
-        let len = password.len();
-        let bit = memory.bit();
-        let sum = memory.sum();
-        let xor = memory.xor();
-        if !pattern1[len][bit][sum][xor] {
-            return;
+pub struct BestFirstResult {
+    pub results: Vec<SearchResult>,
+    // キューから捨てた途中のパスワードの数。0でなければ見逃した解がありうる
+    pub dropped: u64,
+    // --limitで止めずに、キューが空になるまで探索した
+    pub exhausted: bool,
+}
+
+// 同じ長さのターゲットを、1回の探索でまとめられる64個ずつに分ける
+fn groups_by_len(targets: &[PartialMemory]) -> Vec<Vec<PartialMemory>> {
+    let mut lens: Vec<_> = targets.iter().map(|m| m.len()).collect();
+    lens.sort_unstable();
+    lens.dedup();
+    let mut groups = Vec::new();
+    for len in lens {
+        let same: Vec<_> = targets.iter().filter(|m| m.len() == len).cloned().collect();
+        groups.extend(same.chunks(64).map(|c| c.to_vec()));
+    }
+    groups
+}
+
+// --shardで分けたときに、あとでmergeできるように残す担当分の結果
+// 辞書と探索の結果を変えるオプションのハッシュも書いておき、違う条件の結果を混ぜないようにする
+struct ShardFile {
+    path: PathBuf,
+    s: String,
+}
+
+impl ShardFile {
+    // 書き出す先は--shard-file。省略時はdirの下のshard-i-of-n.tsv
+    fn new(dict: &Dict, dir: &Path) -> Option<Self> {
+        let shard = OPT.shard?;
+        let mut hasher = DefaultHasher::new();
+        dict.hash(&mut hasher);
+        let path = OPT
+            .shard_file
+            .clone()
+            .unwrap_or_else(|| dir.join(format!("shard-{}-of-{}.tsv", shard.index, shard.count)));
+        let s = format!(
+            "# shard {}/{}\n# setup {:016x} {:016x}\n",
+            shard.index,
+            shard.count,
+            hasher.finish(),
+            OPT.search_hash()
+        );
+        Some(Self { path, s })
+    }
+
+    fn add(&mut self, target: &PartialMemory, result: &SearchResult) {
+        self.s += &format!("# target {}\n", target.to_hex());
+        for password in &result.passwords {
+            self.s += &format!("{}\t{}\n", target.to_hex(), to_string(password));
         }
+    }
 
-        let s0 = memory.checkdigit2[0] as usize;
-        let s1 = memory.checkdigit2[1] as usize;
-        let s2 = memory.checkdigit5[0] as usize;
-        if !pattern2[len][s0][s1][s2] {
-            return;
+    fn write(&self) {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(&self.path, &self.s).unwrap();
+        eprintln!("wrote {}", self.path.display());
+    }
+}
+
+// 長さごとにまとめて探索する
+pub fn dict_search(expected_memories: &[PartialMemory]) {
+    let mut searcher = Searcher::new();
+    searcher.near_miss = OPT.near_miss;
+    searcher.shard = OPT.shard;
+    searcher.progress = OPT.progress;
+
+    let mut shard_file = ShardFile::new(&searcher.dict, Path::new("results"));
+    let mut stats_tables = Vec::new();
+    for group in groups_by_len(expected_memories) {
+        let len = group[0].len();
+        let results = searcher.search_many(&group);
+
+        if let Some(shard_file) = &mut shard_file {
+            for (target, result) in group.iter().zip(&results) {
+                shard_file.add(target, result);
+            }
+        }
+
+        if searcher.lm.is_some() {
+            for (target, result) in group.iter().zip(&results) {
+                println!("ranked by language model for {}:", target.to_hex());
+                for (password, score) in result.passwords.iter().zip(&result.scores) {
+                    println!("{:.2}\t{}", score, to_string(password));
+                }
+            }
         }
 
-        if OPT.verbose {
+        for (target, result) in group.iter().zip(&results) {
+            if result.near_misses.is_empty() {
+                continue;
+            }
+            println!("near misses for {}:", target.to_hex());
+            for near_miss in &result.near_misses {
+                println!("{}", describe_near_miss(target, near_miss));
+            }
+        }
+
+        // 枝刈りの内訳。バリデータの列は、その長さのパスワードに単語を足すのを弾いた数
+        let stats = results[0].stats.clone();
+        eprintln!("pruning statistics for length {}:", len);
+        eprint!("{}", stats.to_text());
+        stats_tables.push(stats);
+    }
+
+    if let Some(path) = &OPT.stats_json {
+        let json = serde_json::to_string_pretty(&stats_tables).unwrap();
+        std::fs::write(path, json + "\n").unwrap();
+        eprintln!("wrote {}", path.display());
+    }
+
+    if let Some(shard_file) = shard_file {
+        shard_file.write();
+    }
+}
+
+// もっともらしいパスワードから順に、長さごとに探索する
+pub fn best_search(opt: &BestOpt, targets: &[PartialMemory]) {
+    let mut searcher = Searcher::new();
+    searcher.near_miss = OPT.near_miss;
+
+    for group in groups_by_len(targets) {
+        let len = group[0].len();
+        let result =
+            searcher.best_first(&group, opt.beam, opt.limit, |i, password, words, score| {
+                if group.len() == 1 {
+                    println!("find: {}\t{:.2}\t{}", to_string(password), score, words);
+                } else {
+                    println!(
+                        "find: {}\t{:.2}\t{} ({})",
+                        to_string(password),
+                        score,
+                        words,
+                        group[i].to_hex()
+                    );
+                }
+            });
+
+        for (target, result) in group.iter().zip(&result.results) {
+            for near_miss in &result.near_misses {
+                println!("{}", describe_near_miss(target, near_miss));
+            }
+        }
+        if result.dropped > 0 {
             eprintln!(
-                "checking: {}",
-                password
-                    .iter()
-                    .map(|&p| CODE2CHAR[CHAR_CODES[p] as usize])
-                    .collect::<String>()
+                "dropped {} partial passwords from the queue (--beam {}); some solutions may be missing or out of order",
+                result.dropped, opt.beam
             );
         }
+        if !result.exhausted {
+            eprintln!("stopped after {} passwords (--limit)", opt.limit.unwrap());
+        }
+        eprintln!("pruning statistics for length {}:", len);
+        eprint!("{}", result.results[0].stats.to_text());
+    }
+}
+
+// 1文字の単語を少ししか使わないパスワードから順に、長さごとに探索する
+pub fn deepen_search(opt: &DeepenOpt, targets: &[PartialMemory]) {
+    let mut searcher = Searcher::new();
+    searcher.near_miss = OPT.near_miss;
+    searcher.progress = OPT.progress;
 
-        if len == expected_memory.len() {
-            if memory == expected_memory {
-                println!("find: {:?}, {}", &password, to_string(password));
+    for group in groups_by_len(targets) {
+        let len = group[0].len();
+        let max_fillers = opt.max_fillers.unwrap_or(len);
+        // 解はdfs_dictが見つけたときに表示する。同じ回で区切り方が違うだけのものや前の回で見つけたものは表示しない
+        searcher.deepen(&group, max_fillers, |fillers, results| {
+            let found: usize = results.iter().map(|r| r.passwords.len()).sum();
+            eprintln!(
+                "up to {} single-character words: {} new passwords in {:.1}s",
+                fillers,
+                found,
+                results[0].elapsed.as_secs_f64()
+            );
+            for (target, result) in group.iter().zip(results) {
+                for near_miss in &result.near_misses {
+                    println!("{}", describe_near_miss(target, near_miss));
+                }
             }
+            eprint!("{}", results[0].stats.to_text());
+        });
+    }
+}
 
-            return;
+// --shardごとの結果ファイルをまとめる。担当分の抜けや重なりや、辞書やオプションの違いがあればエラー
+pub fn merge_shards(files: &[String]) -> Result<String, String> {
+    let mut count = None;
+    let mut seen = HashSet::new();
+    let mut setups = HashSet::new();
+    let mut targets = Vec::new();
+    let mut found = std::collections::BTreeSet::new();
+    for s in files {
+        setups.insert(s.lines().find(|line| line.starts_with("# setup ")));
+        for line in s.lines() {
+            if line.starts_with("# setup ") {
+                continue;
+            } else if let Some(shard) = line.strip_prefix("# shard ") {
+                let shard: Shard = shard.parse()?;
+                if *count.get_or_insert(shard.count) != shard.count {
+                    return Err(format!("shard counts differ: {}", line));
+                }
+                if !seen.insert(shard.index) {
+                    return Err(format!("shard {} appears twice", shard.index));
+                }
+            } else if let Some(target) = line.strip_prefix("# target ") {
+                if !targets.iter().any(|t| t == target) {
+                    targets.push(target.to_string());
+                }
+            } else if !line.is_empty() {
+                found.insert(line.to_string());
+            }
         }
+    }
 
-        dict.words.par_iter().for_each(|word| {
-            let mut password = password.to_vec();
-            if let Some(memory) = next(word, expected_memory, memory, &mut password) {
-                dfs_dict(
-                    dict,
-                    pattern1,
-                    pattern2,
-                    expected_memory,
-                    &memory,
-                    &password,
+    let count = count.ok_or("no shard header")?;
+    if setups.len() > 1 {
+        return Err("shards were searched with different dictionaries or options".to_string());
+    }
+    let missing: Vec<_> = (0..count).filter(|i| !seen.contains(i)).collect();
+    if !missing.is_empty() {
+        return Err(format!("missing shards {:?} of {}", missing, count));
+    }
+
+    let mut s = String::new();
+    for target in &targets {
+        s += &format!("# target {}\n", target);
+    }
+    for line in found {
+        s += &line;
+        s += "\n";
+    }
+    Ok(s)
+}
+
+pub fn merge(opt: &MergeOpt) {
+    let files: Vec<_> = opt
+        .files
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .unwrap_or_else(|e| panic!("cannot open {}: {}", path.display(), e))
+        })
+        .collect();
+    let merged = merge_shards(&files).unwrap_or_else(|e| panic!("{}", e));
+    match &opt.output {
+        Some(path) => std::fs::write(path, merged).unwrap(),
+        None => print!("{}", merged),
+    }
+}
+
+// 登録簿のターゲットをまとめて探索し、ターゲットごとに結果を書き出す
+// 同じ長さのターゲットは1回の探索でまとめて調べる
+pub fn batch_search(opt: &SearchOpt) {
+    let mut targets: Vec<_> = load_registry(&opt.targets)
+        .into_iter()
+        .filter(|t| opt.all || t.solution.is_none())
+        .collect();
+    targets.sort_by_key(|t| t.memory.len());
+
+    let mut searcher = Searcher::new();
+    searcher.near_miss = OPT.near_miss;
+    searcher.shard = OPT.shard;
+    searcher.progress = OPT.progress;
+
+    // 分割したときは、ほかの担当分を上書きしないよう、ターゲットごとの結果と集計を分けて置く
+    let mut shard_file = ShardFile::new(&searcher.dict, &opt.output_dir);
+    let output_dir = match OPT.shard {
+        Some(shard) => opt
+            .output_dir
+            .join(format!("shard-{}-of-{}", shard.index, shard.count)),
+        None => opt.output_dir.clone(),
+    };
+    std::fs::create_dir_all(&output_dir).unwrap();
+    let mut summary = String::from("name\tlength\tfound\tnear\tnodes\tseconds\n");
+    for group in targets.chunk_by(|a, b| a.memory.len() == b.memory.len()) {
+        let len = group[0].memory.len();
+        for chunk in group.chunks(64) {
+            eprintln!(
+                "{}文字: {}",
+                len,
+                chunk
+                    .iter()
+                    .map(|t| t.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+
+            let memories: Vec<_> = chunk.iter().map(|t| t.memory.clone()).collect();
+            let results = searcher.search_many(&memories);
+
+            for (target, result) in chunk.iter().zip(results) {
+                if let Some(shard_file) = &mut shard_file {
+                    shard_file.add(&target.memory, &result);
+                }
+
+                let mut s = String::new();
+                for (i, password) in result.passwords.iter().enumerate() {
+                    s += &to_string(password);
+                    // 言語モデルがあれば、もっともらしい順にlog10確率を付けて書く
+                    if let Some(score) = result.scores.get(i) {
+                        s += &format!("\t{:.2}", score);
+                    }
+                    s += "\n";
+                }
+                std::fs::write(output_dir.join(format!("{}.txt", target.name)), s).unwrap();
+
+                if searcher.near_miss > 0 {
+                    let mut s = String::new();
+                    for near_miss in &result.near_misses {
+                        s += &describe_near_miss(&target.memory, near_miss);
+                        s += "\n";
+                    }
+                    let path = output_dir.join(format!("{}.near.tsv", target.name));
+                    std::fs::write(path, s).unwrap();
+                }
+
+                let line = format!(
+                    "{}\t{}\t{}\t{}\t{}\t{:.1}\n",
+                    target.name,
+                    len,
+                    result.passwords.len(),
+                    result.near_misses.len(),
+                    result.nodes,
+                    result.elapsed.as_secs_f64()
                 );
+                eprint!("{}", line);
+                summary += &line;
             }
-        });
+            std::fs::write(output_dir.join("summary.tsv"), &summary).unwrap();
+            if let Some(shard_file) = &shard_file {
+                shard_file.write();
+            }
+        }
+
+        // この長さの到達可能な状態の表はもう使わない
+        searcher.reachable.remove(&len);
     }
+}
 
-    eprintln!("start search");
+#[test]
+fn search_short_target() {
+    let kid: PartialMemory = "00 51 03 3A E9 08 23 07".parse().unwrap();
+    let result = Searcher::new().search_many(&[kid]).pop().unwrap();
+    assert!(result.passwords.iter().any(|p| to_string(p) == "KID"));
+}
 
-    let memory = Memory::new(expected_memory.len() as u8);
-    let password = Vec::new();
-    dfs_dict(
-        &dict,
-        &pattern1,
-        &pattern2,
-        expected_memory,
-        &memory,
-        &password,
+#[test]
+fn search_same_length_targets_at_once() {
+    let kid: PartialMemory = "00 51 03 3A E9 08 23 07".parse().unwrap();
+    let mut other = Memory::new(3);
+    forward_word(&mut other, &crate::domain::to_charcode_indices("OKA"));
+
+    let results = Searcher::new().search_many(&[kid, other.into()]);
+    assert!(results[0].passwords.iter().any(|p| to_string(p) == "KID"));
+    assert!(results[1].passwords.iter().any(|p| to_string(p) == "OKA"));
+    assert!(results[0].passwords.iter().all(|p| to_string(p) != "OKA"));
+}
+
+#[test]
+fn search_partial_target() {
+    let kid: PartialMemory = "00 51 03 3A ?? 08 ?? ??".parse().unwrap();
+    let result = Searcher::new().search_many(&[kid]).pop().unwrap();
+    assert!(result.passwords.iter().any(|p| to_string(p) == "KID"));
+}
+
+#[test]
+fn report_near_misses() {
+    // KIDのbitだけを1ずらしたもの
+    let target: PartialMemory = "00 51 03 3A E9 08 23 08".parse().unwrap();
+    let mut searcher = Searcher::new();
+    searcher.near_miss = 1;
+    let result = searcher
+        .search_many(std::slice::from_ref(&target))
+        .pop()
+        .unwrap();
+    assert!(result.passwords.is_empty());
+
+    let kid = result
+        .near_misses
+        .iter()
+        .find(|n| to_string(&n.password) == "KID")
+        .unwrap();
+    assert_eq!((6, 7), matched_bytes(&target, &kid.memory));
+    assert_eq!(
+        vec!["checkdigit2", "sum", "xor"],
+        matched_fields(&target, &kid.memory)
     );
 }
+
+#[test]
+fn shards_cover_search_once() {
+    let target: PartialMemory = "?? ?? 03 3A ?? ?? ?? 07".parse().unwrap();
+    let mut searcher = Searcher::new();
+    let all = searcher
+        .search_many(std::slice::from_ref(&target))
+        .pop()
+        .unwrap();
+
+    // 単語の数より多く分けると2番目の単語まで使う
+    for (count, depth) in [(3, 1), (searcher.dict.words.len() + 5, 2)] {
+        let mut units: Vec<_> = (0..count)
+            .flat_map(|index| shard_units(&searcher.dict, Shard { index, count }))
+            .collect();
+        units.sort();
+        let mut expected = work_units(&searcher.dict, depth);
+        expected.sort();
+        assert_eq!(expected, units);
+    }
+
+    let units = work_units(&searcher.dict, 2);
+    let result = searcher
+        .search_units(std::slice::from_ref(&target), &units)
+        .pop()
+        .unwrap();
+    assert_eq!(all.passwords, result.passwords);
+}
+
+#[test]
+fn stats_do_not_depend_on_units() {
+    let target: PartialMemory = "?? ?? 03 3A ?? ?? ?? 07".parse().unwrap();
+    let mut searcher = Searcher::new();
+    let tables: Vec<_> = [0, 2]
+        .iter()
+        .map(|&depth| {
+            let units = work_units(&searcher.dict, depth);
+            let result = searcher
+                .search_units(std::slice::from_ref(&target), &units)
+                .pop()
+                .unwrap();
+            serde_json::to_string(&result.stats).unwrap()
+        })
+        .collect();
+    assert_eq!(tables[0], tables[1]);
+}
+
+#[test]
+fn best_first_finds_the_same_passwords() {
+    let target: PartialMemory = "?? ?? 03 3A ?? ?? ?? 07".parse().unwrap();
+    let mut searcher = Searcher::new();
+    let mut all = searcher
+        .search_many(std::slice::from_ref(&target))
+        .pop()
+        .unwrap()
+        .passwords;
+    all.dedup();
+
+    let mut scores = Vec::new();
+    let result = searcher.best_first(
+        std::slice::from_ref(&target),
+        1 << 20,
+        None,
+        |_, _, _, score| scores.push(score),
+    );
+    assert_eq!(0, result.dropped);
+    assert!(scores.windows(2).all(|w| w[0] >= w[1]), "{:?}", scores);
+    let mut passwords = result.results[0].passwords.clone();
+    passwords.sort();
+    assert_eq!(all, passwords);
+}
+
+#[test]
+fn merge_shard_files() {
+    let files = [
+        "# shard 0/2\n# setup 01 02\n# target 00 51 03 3A E9 08 23 07\n00 51 03 3A E9 08 23 07\tKID\n",
+        "# shard 1/2\n# setup 01 02\n# target 00 51 03 3A E9 08 23 07\n",
+    ]
+    .map(str::to_string);
+    let merged = merge_shards(&files).unwrap();
+    assert_eq!(
+        "# target 00 51 03 3A E9 08 23 07\n00 51 03 3A E9 08 23 07\tKID\n",
+        merged
+    );
+    assert!(merge_shards(&files[..1]).is_err());
+    assert!(merge_shards(&[files[0].clone(), files[0].clone()]).is_err());
+    // 違うオプションで探索した担当分は混ぜない
+    let other = files[1].replace("# setup 01 02", "# setup 01 03");
+    assert!(merge_shards(&[files[0].clone(), other]).is_err());
+}
+
+#[test]
+fn parse_weighted_dict() {
+    let entries = parse_dict(
+        "; コメント\nTOKYO w=3 #place\nKAPPA #name #game-term w=0.5 ; KAPPA\nA B TOKYO w=9\n",
+    )
+    .unwrap();
+    let words: Vec<_> = entries.iter().map(|e| e.word).collect();
+    assert_eq!(vec!["TOKYO", "KAPPA", "A", "B"], words);
+    assert_eq!(3.0, entries[0].weight);
+    assert_eq!(vec!["place"], entries[0].tags);
+    assert_eq!(0.5, entries[1].weight);
+    assert_eq!(vec!["name", "game-term"], entries[1].tags);
+    assert_eq!(1.0, entries[2].weight);
+
+    assert!(parse_dict("#name TOKYO").is_err());
+    assert!(parse_dict("TOKYO w=0").is_err());
+    assert!(parse_dict("TOKYO\nKAPPA w=x")
+        .unwrap_err()
+        .starts_with("2:"));
+}
+
+#[test]
+fn deepen_reports_each_password_once() {
+    use crate::domain::to_charcode_indices;
+    use crate::pruning::FnValidator;
+
+    // HENTAIOSUGIはHENTAI + OSUGIでもHENTAIOSUG + Iでも作れる
+    let words = ["HENTAI", "OSUGI", "HENTAIOSUG", "I", "A", "K"];
+    let mut searcher = Searcher::new();
+    searcher.dict = Dict {
+        words: words.iter().map(|w| to_charcode_indices(w)).collect(),
+        info: words
+            .iter()
+            .map(|_| WordInfo {
+                weight: 1.0,
+                tags: vec![],
+                origin: None,
+            })
+            .collect(),
+    };
+    searcher.transitions = Transitions::new(&searcher.dict);
+    let k = to_charcode_indices("K")[0];
+    searcher.validators.register(Box::new(FnValidator {
+        name: "no_k".to_string(),
+        check: move |_: &Memory, _: &[usize], word: &[usize]| !word.contains(&k),
+    }));
+
+    let target: PartialMemory = "64 98 0B 15 91 18 B1 15".parse().unwrap();
+    let mut all = searcher
+        .search_many(std::slice::from_ref(&target))
+        .remove(0);
+    all.passwords.dedup();
+
+    let mut rounds = Vec::new();
+    searcher.deepen(std::slice::from_ref(&target), 11, |_, results| {
+        rounds.push(results[0].passwords.clone());
+    });
+    assert_eq!(12, rounds.len());
+    // 辞書の単語だけでできたものは最初の回で見つかり、1文字の単語を許した回では繰り返さない
+    let hentaiosugi = to_charcode_indices("HENTAIOSUGI");
+    assert_eq!(vec![hentaiosugi.clone()], rounds[0]);
+    assert!(rounds[1..].iter().all(|r| !r.contains(&hentaiosugi)));
+
+    let mut found: Vec<_> = rounds.into_iter().flatten().collect();
+    let len = found.len();
+    found.sort();
+    found.dedup();
+    assert_eq!(len, found.len());
+    assert_eq!(all.passwords, found);
+
+    // registerで足したバリデータは残り、compositionは元に戻る
+    let names = searcher.validators.names();
+    assert!(names.contains(&"no_k"));
+    assert!(!names.contains(&"composition"));
+}
+
+#[test]
+fn group_targets_by_64() {
+    let targets: Vec<PartialMemory> = (0..70)
+        .map(|i| format!("{:02X} ?? 03 ?? ?? ?? ?? ??", i).parse().unwrap())
+        .chain(["?? ?? 08 ?? ?? ?? ?? ??".parse().unwrap()])
+        .collect();
+    let sizes: Vec<_> = groups_by_len(&targets)
+        .iter()
+        .map(|g| (g[0].len(), g.len()))
+        .collect();
+    assert_eq!(vec![(3, 64), (3, 6), (8, 1)], sizes);
+}
@@ -2,7 +2,8 @@
 
 use once_cell::sync::Lazy;
 
-use crate::cpu::Memory;
+// パスワードの最大文字数
+pub const MAX_PASSWORD_LEN: usize = 14;
 
 // 圧縮した文字集合
 pub const CHAR_CODES: [u8; 42] = [
@@ -58,62 +59,23 @@ pub static CODE2CHAR: Lazy<[char; 0x100]> = Lazy::new(|| {
     table
 });
 
-// 未知のパスワード1(11桁)を解析する
-// yokai03.exe 64 98 0B 15 91 18 B1 15
-// 64: a[10]の逆順
-// 98: a[9]の逆順
-// 0B: 長さ
-// 15: sum(a) + cnt(checkdigit2[0] >= 0xE5)
-// 91: sum(checkdigit2[1])
-// 18: xor が 0x18
-// B1: a[10] + ror(a[9] + ror(a[8] + ...))
-// 15: 0x15 bit
-
-// KID
-pub const EXPECTED_MEMORY_KID: Memory = Memory {
-    checkdigit2: [0x00, 0x51],
-    password_len: 0x03,
-    checkdigit5: [0x3A, 0xE9, 0x08, 0x23, 0x07],
-};
-
-// 8文字 818-6104
-pub const EXPECTED_MEMORY_8: Memory = Memory {
-    checkdigit2: [0xDC, 0xD9],
-    password_len: 0x08,
-    checkdigit5: [0xA3, 0xE3, 0x17, 0x28, 0x15],
-};
-
-// 11文字 HENTAIOSUGI
-// yokai03.exe 64 98 0B 15 91 18 B1 15
-pub const EXPECTED_MEMORY_11: Memory = Memory {
-    checkdigit2: [0x64, 0x98],
-    password_len: 0x0B,
-    checkdigit5: [0x15, 0x91, 0x18, 0xB1, 0x15],
-};
-
-// 14文字
-// yokai03.exe 65 94 0E AC E9 07 33 25
-pub const EXPECTED_MEMORY_14: Memory = Memory {
-    checkdigit2: [0x65, 0x94],
-    password_len: 0x0E,
-    checkdigit5: [0xAC, 0xE9, 0x07, 0x33, 0x25],
-};
-
-// 14文字(monitorのところにあるハッシュ値)
-pub const EXPECTED_MEMORY_14_2: Memory = Memory {
-    checkdigit2: [0x51, 0x62],
-    password_len: 0x0E,
-    checkdigit5: [0xFD, 0x39, 0x03, 0xCB, 0x26],
-};
-
 pub fn to_charcode_indices(password: &str) -> Vec<usize> {
+    try_to_charcode_indices(password)
+        .unwrap_or_else(|| panic!("{} contains an invalid character", password))
+}
+
+// パスワードに使えない文字が含まれていればNone
+pub fn try_to_charcode_indices(password: &str) -> Option<Vec<usize>> {
     let mut result = Vec::new();
     for c in password.chars() {
-        let c = CODE2CHAR.iter().position(|&x| x == c).unwrap();
-        let i = CHAR_CODES.iter().position(|&x| x as usize == c).unwrap();
+        if c == '?' {
+            return None;
+        }
+        let c = CODE2CHAR.iter().position(|&x| x == c)?;
+        let i = CHAR_CODES.iter().position(|&x| x as usize == c)?;
         result.push(i);
     }
-    result
+    Some(result)
 }
 
 pub fn is_number(index: usize) -> bool {
@@ -0,0 +1,731 @@
+// チェックサムルーチンを差分検証するための、HuC6280(6502系)の最小限のインタプリタ
+// https://www.pagetable.com/c64ref/6502/?tab=2
+//
+// - MPRによるバンク切り替えは扱わず、64KBのフラットな空間とみなす
+// - ゼロページは$2000、スタックは$2100に割り付けられている(HuC6280の仕様)
+// - 10進モード、Tフラグ(SET命令)、ブロック転送、VDC関連の命令は未対応
+use std::io::Read;
+
+use crate::cpu::{forward_step, Memory};
+use crate::domain::{to_string, CHAR_CODES};
+use crate::opt::CrosscheckOpt;
+use crate::rng::SplitMix64;
+
+const ZERO_PAGE: u16 = 0x2000;
+const STACK_PAGE: u16 = 0x2100;
+
+// Memoryが配置されているアドレス($31F4 ~ $31FB)
+pub const MEMORY_ADDR: u16 = 0x31F4;
+
+const FLAG_C: u8 = 0x01;
+const FLAG_Z: u8 = 0x02;
+const FLAG_I: u8 = 0x04;
+const FLAG_D: u8 = 0x08;
+const FLAG_B: u8 = 0x10;
+const FLAG_V: u8 = 0x40;
+const FLAG_N: u8 = 0x80;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Imm,
+    Zp,
+    ZpX,
+    ZpY,
+    Abs,
+    AbsX,
+    AbsY,
+    // (zp,x)
+    IndX,
+    // (zp),y
+    IndY,
+    // (zp)
+    Ind,
+}
+
+pub struct Huc6280 {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub pc: u16,
+    pub ram: Vec<u8>,
+}
+
+impl Default for Huc6280 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Huc6280 {
+    pub fn new() -> Self {
+        Self {
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0xFF,
+            p: FLAG_I,
+            pc: 0,
+            ram: vec![0; 0x10000],
+        }
+    }
+
+    pub fn load(&mut self, addr: u16, bytes: &[u8]) {
+        let addr = addr as usize;
+        assert!(
+            addr + bytes.len() <= self.ram.len(),
+            "image does not fit at ${:04X}",
+            addr
+        );
+        self.ram[addr..addr + bytes.len()].copy_from_slice(bytes);
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    pub fn write(&mut self, addr: u16, v: u8) {
+        self.ram[addr as usize] = v;
+    }
+
+    pub fn flag(&self, f: u8) -> bool {
+        self.p & f != 0
+    }
+
+    pub fn set_flag(&mut self, f: u8, on: bool) {
+        if on {
+            self.p |= f;
+        } else {
+            self.p &= !f;
+        }
+    }
+
+    pub fn write_memory(&mut self, memory: &Memory) {
+        self.load(MEMORY_ADDR, &memory.to_bytes());
+    }
+
+    pub fn read_memory(&self) -> Memory {
+        let mut bytes = [0; 8];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = self.read(MEMORY_ADDR + i as u16);
+        }
+        Memory::from_bytes(bytes)
+    }
+
+    fn set_nz(&mut self, v: u8) {
+        self.set_flag(FLAG_Z, v == 0);
+        self.set_flag(FLAG_N, v & 0x80 != 0);
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let v = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let lo = self.fetch() as u16;
+        let hi = self.fetch() as u16;
+        hi << 8 | lo
+    }
+
+    fn zp(offset: u8) -> u16 {
+        ZERO_PAGE + offset as u16
+    }
+
+    fn read_zp16(&self, offset: u8) -> u16 {
+        let lo = self.read(Self::zp(offset)) as u16;
+        let hi = self.read(Self::zp(offset.wrapping_add(1))) as u16;
+        hi << 8 | lo
+    }
+
+    fn push(&mut self, v: u8) {
+        self.write(STACK_PAGE + self.s as u16, v);
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    fn pop(&mut self) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        self.read(STACK_PAGE + self.s as u16)
+    }
+
+    fn addr(&mut self, mode: Mode) -> u16 {
+        match mode {
+            Mode::Imm => {
+                let addr = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                addr
+            }
+            Mode::Zp => {
+                let o = self.fetch();
+                Self::zp(o)
+            }
+            Mode::ZpX => {
+                let o = self.fetch();
+                Self::zp(o.wrapping_add(self.x))
+            }
+            Mode::ZpY => {
+                let o = self.fetch();
+                Self::zp(o.wrapping_add(self.y))
+            }
+            Mode::Abs => self.fetch16(),
+            Mode::AbsX => self.fetch16().wrapping_add(self.x as u16),
+            Mode::AbsY => self.fetch16().wrapping_add(self.y as u16),
+            Mode::IndX => {
+                let o = self.fetch();
+                self.read_zp16(o.wrapping_add(self.x))
+            }
+            Mode::IndY => {
+                let o = self.fetch();
+                self.read_zp16(o).wrapping_add(self.y as u16)
+            }
+            Mode::Ind => {
+                let o = self.fetch();
+                self.read_zp16(o)
+            }
+        }
+    }
+
+    fn operand(&mut self, mode: Mode) -> u8 {
+        let addr = self.addr(mode);
+        self.read(addr)
+    }
+
+    // Noneはアキュムレータ
+    fn rmw(&mut self, mode: Option<Mode>, f: impl Fn(&mut Self, u8) -> u8) {
+        match mode {
+            None => {
+                let v = f(self, self.a);
+                self.a = v;
+                self.set_nz(v);
+            }
+            Some(mode) => {
+                let addr = self.addr(mode);
+                let v = f(self, self.read(addr));
+                self.write(addr, v);
+                self.set_nz(v);
+            }
+        }
+    }
+
+    fn adc(&mut self, m: u8) -> Result<(), String> {
+        if self.flag(FLAG_D) {
+            return Err("decimal mode is not supported".to_string());
+        }
+        let a = self.a as u16;
+        let r = a + m as u16 + (self.p & FLAG_C) as u16;
+        let r8 = r as u8;
+        self.set_flag(FLAG_C, r > 0xFF);
+        self.set_flag(FLAG_V, (!(self.a ^ m) & (self.a ^ r8) & 0x80) != 0);
+        self.a = r8;
+        self.set_nz(r8);
+        Ok(())
+    }
+
+    fn compare(&mut self, reg: u8, m: u8) {
+        self.set_flag(FLAG_C, reg >= m);
+        self.set_nz(reg.wrapping_sub(m));
+    }
+
+    fn bit(&mut self, m: u8, immediate: bool) {
+        self.set_flag(FLAG_Z, self.a & m == 0);
+        if !immediate {
+            self.set_flag(FLAG_N, m & 0x80 != 0);
+            self.set_flag(FLAG_V, m & 0x40 != 0);
+        }
+    }
+
+    fn branch(&mut self, cond: bool) {
+        let offset = self.fetch() as i8;
+        if cond {
+            self.pc = self.pc.wrapping_add(offset as u16);
+        }
+    }
+
+    fn asl(&mut self, v: u8) -> u8 {
+        self.set_flag(FLAG_C, v & 0x80 != 0);
+        v << 1
+    }
+
+    fn lsr(&mut self, v: u8) -> u8 {
+        self.set_flag(FLAG_C, v & 0x01 != 0);
+        v >> 1
+    }
+
+    fn rol(&mut self, v: u8) -> u8 {
+        let c = self.p & FLAG_C;
+        self.set_flag(FLAG_C, v & 0x80 != 0);
+        v << 1 | c
+    }
+
+    fn ror(&mut self, v: u8) -> u8 {
+        let c = self.p & FLAG_C;
+        self.set_flag(FLAG_C, v & 0x01 != 0);
+        v >> 1 | c << 7
+    }
+
+    pub fn step(&mut self) -> Result<(), String> {
+        let pc = self.pc;
+        let op = self.fetch();
+
+        // ORA AND EOR ADC STA LDA CMP SBC
+        // 下位2bitが01のものと、65C02で追加された(zp)アドレッシング
+        if (op & 0x03 == 0x01 && op != 0x89) || op & 0x1F == 0x12 {
+            let mode = if op & 0x1F == 0x12 {
+                Mode::Ind
+            } else {
+                [
+                    Mode::IndX,
+                    Mode::Zp,
+                    Mode::Imm,
+                    Mode::Abs,
+                    Mode::IndY,
+                    Mode::ZpX,
+                    Mode::AbsY,
+                    Mode::AbsX,
+                ][(op >> 2 & 7) as usize]
+            };
+            match op >> 5 {
+                0 => {
+                    self.a |= self.operand(mode);
+                    self.set_nz(self.a);
+                }
+                1 => {
+                    self.a &= self.operand(mode);
+                    self.set_nz(self.a);
+                }
+                2 => {
+                    self.a ^= self.operand(mode);
+                    self.set_nz(self.a);
+                }
+                3 => {
+                    let m = self.operand(mode);
+                    self.adc(m)?;
+                }
+                4 => {
+                    let addr = self.addr(mode);
+                    self.write(addr, self.a);
+                }
+                5 => {
+                    self.a = self.operand(mode);
+                    self.set_nz(self.a);
+                }
+                6 => {
+                    let m = self.operand(mode);
+                    self.compare(self.a, m);
+                }
+                _ => {
+                    let m = self.operand(mode);
+                    self.adc(!m)?;
+                }
+            }
+            return Ok(());
+        }
+
+        match op {
+            0x0A => self.rmw(None, Self::asl),
+            0x06 => self.rmw(Some(Mode::Zp), Self::asl),
+            0x16 => self.rmw(Some(Mode::ZpX), Self::asl),
+            0x0E => self.rmw(Some(Mode::Abs), Self::asl),
+            0x1E => self.rmw(Some(Mode::AbsX), Self::asl),
+            0x2A => self.rmw(None, Self::rol),
+            0x26 => self.rmw(Some(Mode::Zp), Self::rol),
+            0x36 => self.rmw(Some(Mode::ZpX), Self::rol),
+            0x2E => self.rmw(Some(Mode::Abs), Self::rol),
+            0x3E => self.rmw(Some(Mode::AbsX), Self::rol),
+            0x4A => self.rmw(None, Self::lsr),
+            0x46 => self.rmw(Some(Mode::Zp), Self::lsr),
+            0x56 => self.rmw(Some(Mode::ZpX), Self::lsr),
+            0x4E => self.rmw(Some(Mode::Abs), Self::lsr),
+            0x5E => self.rmw(Some(Mode::AbsX), Self::lsr),
+            0x6A => self.rmw(None, Self::ror),
+            0x66 => self.rmw(Some(Mode::Zp), Self::ror),
+            0x76 => self.rmw(Some(Mode::ZpX), Self::ror),
+            0x6E => self.rmw(Some(Mode::Abs), Self::ror),
+            0x7E => self.rmw(Some(Mode::AbsX), Self::ror),
+
+            0x1A => self.rmw(None, |_, v| v.wrapping_add(1)),
+            0xE6 => self.rmw(Some(Mode::Zp), |_, v| v.wrapping_add(1)),
+            0xF6 => self.rmw(Some(Mode::ZpX), |_, v| v.wrapping_add(1)),
+            0xEE => self.rmw(Some(Mode::Abs), |_, v| v.wrapping_add(1)),
+            0xFE => self.rmw(Some(Mode::AbsX), |_, v| v.wrapping_add(1)),
+            0x3A => self.rmw(None, |_, v| v.wrapping_sub(1)),
+            0xC6 => self.rmw(Some(Mode::Zp), |_, v| v.wrapping_sub(1)),
+            0xD6 => self.rmw(Some(Mode::ZpX), |_, v| v.wrapping_sub(1)),
+            0xCE => self.rmw(Some(Mode::Abs), |_, v| v.wrapping_sub(1)),
+            0xDE => self.rmw(Some(Mode::AbsX), |_, v| v.wrapping_sub(1)),
+
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
+                let mode = match op {
+                    0xA2 => Mode::Imm,
+                    0xA6 => Mode::Zp,
+                    0xB6 => Mode::ZpY,
+                    0xAE => Mode::Abs,
+                    _ => Mode::AbsY,
+                };
+                self.x = self.operand(mode);
+                self.set_nz(self.x);
+            }
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
+                let mode = match op {
+                    0xA0 => Mode::Imm,
+                    0xA4 => Mode::Zp,
+                    0xB4 => Mode::ZpX,
+                    0xAC => Mode::Abs,
+                    _ => Mode::AbsX,
+                };
+                self.y = self.operand(mode);
+                self.set_nz(self.y);
+            }
+            0x86 | 0x96 | 0x8E => {
+                let mode = match op {
+                    0x86 => Mode::Zp,
+                    0x96 => Mode::ZpY,
+                    _ => Mode::Abs,
+                };
+                let addr = self.addr(mode);
+                self.write(addr, self.x);
+            }
+            0x84 | 0x94 | 0x8C => {
+                let mode = match op {
+                    0x84 => Mode::Zp,
+                    0x94 => Mode::ZpX,
+                    _ => Mode::Abs,
+                };
+                let addr = self.addr(mode);
+                self.write(addr, self.y);
+            }
+            0x64 | 0x74 | 0x9C | 0x9E => {
+                let mode = match op {
+                    0x64 => Mode::Zp,
+                    0x74 => Mode::ZpX,
+                    0x9C => Mode::Abs,
+                    _ => Mode::AbsX,
+                };
+                let addr = self.addr(mode);
+                self.write(addr, 0);
+            }
+
+            0xE0 | 0xE4 | 0xEC => {
+                let mode = match op {
+                    0xE0 => Mode::Imm,
+                    0xE4 => Mode::Zp,
+                    _ => Mode::Abs,
+                };
+                let m = self.operand(mode);
+                self.compare(self.x, m);
+            }
+            0xC0 | 0xC4 | 0xCC => {
+                let mode = match op {
+                    0xC0 => Mode::Imm,
+                    0xC4 => Mode::Zp,
+                    _ => Mode::Abs,
+                };
+                let m = self.operand(mode);
+                self.compare(self.y, m);
+            }
+            0x89 => {
+                let m = self.operand(Mode::Imm);
+                self.bit(m, true);
+            }
+            0x24 | 0x34 | 0x2C | 0x3C => {
+                let mode = match op {
+                    0x24 => Mode::Zp,
+                    0x34 => Mode::ZpX,
+                    0x2C => Mode::Abs,
+                    _ => Mode::AbsX,
+                };
+                let m = self.operand(mode);
+                self.bit(m, false);
+            }
+
+            0x10 => self.branch(!self.flag(FLAG_N)),
+            0x30 => self.branch(self.flag(FLAG_N)),
+            0x50 => self.branch(!self.flag(FLAG_V)),
+            0x70 => self.branch(self.flag(FLAG_V)),
+            0x90 => self.branch(!self.flag(FLAG_C)),
+            0xB0 => self.branch(self.flag(FLAG_C)),
+            0xD0 => self.branch(!self.flag(FLAG_Z)),
+            0xF0 => self.branch(self.flag(FLAG_Z)),
+            0x80 => self.branch(true),
+
+            0x4C => self.pc = self.fetch16(),
+            0x6C => {
+                let ptr = self.fetch16();
+                let lo = self.read(ptr) as u16;
+                let hi = self.read(ptr.wrapping_add(1)) as u16;
+                self.pc = hi << 8 | lo;
+            }
+            0x20 => {
+                let target = self.fetch16();
+                let ret = self.pc.wrapping_sub(1);
+                self.push((ret >> 8) as u8);
+                self.push(ret as u8);
+                self.pc = target;
+            }
+            0x60 => {
+                let lo = self.pop() as u16;
+                let hi = self.pop() as u16;
+                self.pc = (hi << 8 | lo).wrapping_add(1);
+            }
+
+            0x48 => self.push(self.a),
+            0xDA => self.push(self.x),
+            0x5A => self.push(self.y),
+            0x08 => self.push(self.p | FLAG_B),
+            // PLA（pop）でもN, Zフラグが変わる
+            0x68 => {
+                self.a = self.pop();
+                self.set_nz(self.a);
+            }
+            0xFA => {
+                self.x = self.pop();
+                self.set_nz(self.x);
+            }
+            0x7A => {
+                self.y = self.pop();
+                self.set_nz(self.y);
+            }
+            0x28 => self.p = self.pop() & !FLAG_B,
+
+            0xAA => {
+                self.x = self.a;
+                self.set_nz(self.x);
+            }
+            0xA8 => {
+                self.y = self.a;
+                self.set_nz(self.y);
+            }
+            0x8A => {
+                self.a = self.x;
+                self.set_nz(self.a);
+            }
+            0x98 => {
+                self.a = self.y;
+                self.set_nz(self.a);
+            }
+            0xBA => {
+                self.x = self.s;
+                self.set_nz(self.x);
+            }
+            0x9A => self.s = self.x,
+            0x22 => std::mem::swap(&mut self.a, &mut self.x),
+            0x42 => std::mem::swap(&mut self.a, &mut self.y),
+            0x02 => std::mem::swap(&mut self.x, &mut self.y),
+            0x62 => self.a = 0,
+            0x82 => self.x = 0,
+            0xC2 => self.y = 0,
+
+            0xE8 => {
+                self.x = self.x.wrapping_add(1);
+                self.set_nz(self.x);
+            }
+            0xC8 => {
+                self.y = self.y.wrapping_add(1);
+                self.set_nz(self.y);
+            }
+            0xCA => {
+                self.x = self.x.wrapping_sub(1);
+                self.set_nz(self.x);
+            }
+            0x88 => {
+                self.y = self.y.wrapping_sub(1);
+                self.set_nz(self.y);
+            }
+
+            0x18 => self.set_flag(FLAG_C, false),
+            0x38 => self.set_flag(FLAG_C, true),
+            0x58 => self.set_flag(FLAG_I, false),
+            0x78 => self.set_flag(FLAG_I, true),
+            0xB8 => self.set_flag(FLAG_V, false),
+            0xD8 => self.set_flag(FLAG_D, false),
+            0xF8 => self.set_flag(FLAG_D, true),
+
+            // NOP, CSL, CSH
+            0xEA | 0x54 | 0xD4 => {}
+
+            _ => return Err(format!("unsupported opcode {:02X} at ${:04X}", op, pc)),
+        }
+
+        Ok(())
+    }
+
+    // JSRで呼び出したのと同じように実行し、対応するRTSで戻ってきたら終了する
+    pub fn call(&mut self, entry: u16, max_steps: usize) -> Result<(), String> {
+        let s = self.s;
+        let ret = 0xFFFF_u16;
+        self.push((ret >> 8) as u8);
+        self.push(ret as u8);
+        self.pc = entry;
+
+        for _ in 0..max_steps {
+            self.step()?;
+            if self.s == s && self.pc == ret.wrapping_add(1) {
+                return Ok(());
+            }
+        }
+
+        Err(format!("routine did not return in {} steps", max_steps))
+    }
+}
+
+// 1文字分のチェックサム計算をエミュレータで行う
+pub fn emulate_step(
+    cpu: &mut Huc6280,
+    entry: u16,
+    memory: &Memory,
+    a: u8,
+) -> Result<Memory, String> {
+    cpu.write_memory(memory);
+    cpu.a = a;
+    cpu.set_flag(FLAG_D, false);
+    cpu.call(entry, 100_000)?;
+    Ok(cpu.read_memory())
+}
+
+// ランダムなパスワードを1文字ずつ入力し、forward_stepと結果が食い違う最初のケースを返す
+pub fn find_mismatch(
+    cpu: &mut Huc6280,
+    entry: u16,
+    samples: usize,
+    seed: u64,
+) -> Result<Option<(Vec<usize>, Memory, Memory)>, String> {
+    let mut rng = SplitMix64::new(seed);
+
+    for _ in 0..samples {
+        let len = 1 + rng.below(14) as usize;
+        let mut memory = Memory::new(len as u8);
+        let mut password = Vec::new();
+        for _ in 0..len {
+            let c = rng.below(CHAR_CODES.len() as u64) as usize;
+            password.push(c);
+
+            let emulated = emulate_step(cpu, entry, &memory, CHAR_CODES[c])?;
+            forward_step(&mut memory, CHAR_CODES[c]);
+            if emulated != memory {
+                return Ok(Some((password, memory, emulated)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn crosscheck(opt: &CrosscheckOpt) {
+    fn read_file(path: &std::path::Path) -> Vec<u8> {
+        let mut file = std::fs::File::open(path)
+            .unwrap_or_else(|e| panic!("cannot open {}: {}", path.display(), e));
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    let mut cpu = Huc6280::new();
+    if let Some(ram) = &opt.ram {
+        cpu.load(opt.ram_addr, &read_file(ram));
+    }
+    cpu.load(opt.load_addr, &read_file(&opt.routine));
+
+    let entry = opt.entry.unwrap_or(opt.load_addr);
+    match find_mismatch(&mut cpu, entry, opt.samples, opt.seed) {
+        Ok(None) => println!("ok: {} passwords matched", opt.samples),
+        Ok(Some((password, expected, actual))) => {
+            println!("mismatch: {}", to_string(&password));
+            println!("  forward_step: {:?}", expected);
+            println!("  emulator    : {:?}", actual);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("emulation failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// cpu.rsの実装をもとに手で組み立てたルーチン
+// 実機のバイト列が手元にない環境でも、インタプリタとforward_stepの両方を検証できるようにしておく
+#[cfg(test)]
+const REFERENCE_ROUTINE: [u8; 102] = [
+    0x48, //             PHA
+    0x85, 0x00, //       STA $00
+    0xA2, 0x08, //       LDX #$08
+    0x06, 0x00, //       loop1: ASL $00
+    0x6E, 0xF4, 0x31, // ROR $31F4
+    0x6E, 0xF5, 0x31, // ROR $31F5
+    0x90, 0x10, //       BCC skip
+    0xAD, 0xF4, 0x31, // LDA $31F4
+    0x49, 0x84, //       EOR #$84
+    0x8D, 0xF4, 0x31, // STA $31F4
+    0xAD, 0xF5, 0x31, // LDA $31F5
+    0x49, 0x08, //       EOR #$08
+    0x8D, 0xF5, 0x31, // STA $31F5
+    0xCA, //             skip: DEX
+    0xD0, 0xE3, //       BNE loop1
+    0xAD, 0xF4, 0x31, // LDA $31F4
+    0xC9, 0xE5, //       CMP #$E5
+    0x68, //             PLA
+    0x48, //             PHA
+    0x6D, 0xF7, 0x31, // ADC $31F7
+    0x8D, 0xF7, 0x31, // STA $31F7
+    0xAD, 0xF8, 0x31, // LDA $31F8
+    0x6D, 0xF5, 0x31, // ADC $31F5
+    0x8D, 0xF8, 0x31, // STA $31F8
+    0x68, //             PLA
+    0x48, //             PHA
+    0x4D, 0xF9, 0x31, // EOR $31F9
+    0x8D, 0xF9, 0x31, // STA $31F9
+    0x6E, 0xFA, 0x31, // ROR $31FA
+    0x68, //             PLA
+    0x48, //             PHA
+    0x6D, 0xFA, 0x31, // ADC $31FA
+    0x8D, 0xFA, 0x31, // STA $31FA
+    0xAD, 0xFB, 0x31, // LDA $31FB
+    0x69, 0x00, //       ADC #$00
+    0x8D, 0xFB, 0x31, // STA $31FB
+    0x68, //             PLA
+    0x85, 0x00, //       STA $00
+    0xA2, 0x08, //       LDX #$08
+    0x46, 0x00, //       loop2: LSR $00
+    0xAD, 0xFB, 0x31, // LDA $31FB
+    0x69, 0x00, //       ADC #$00
+    0x8D, 0xFB, 0x31, // STA $31FB
+    0xCA, //             DEX
+    0xD0, 0xF3, //       BNE loop2
+    0x60, //             RTS
+];
+
+#[test]
+fn reference_routine_matches_forward_step() {
+    let mut cpu = Huc6280::new();
+    cpu.load(0xE000, &REFERENCE_ROUTINE);
+    let mismatch = find_mismatch(&mut cpu, 0xE000, 200, 1).unwrap();
+    assert!(mismatch.is_none());
+}
+
+#[test]
+fn flags() {
+    let mut cpu = Huc6280::new();
+    // CLC; LDA #$7F; ADC #$01 => V, N
+    // CMP #$80 => Z, C
+    // PHA; LDA #$01; PLA => N
+    cpu.load(
+        0xE000,
+        &[
+            0x18, 0xA9, 0x7F, 0x69, 0x01, 0xC9, 0x80, 0x48, 0xA9, 0x01, 0x68,
+        ],
+    );
+    cpu.pc = 0xE000;
+    for _ in 0..3 {
+        cpu.step().unwrap();
+    }
+    assert_eq!(0x80, cpu.a);
+    assert!(cpu.flag(FLAG_V) && cpu.flag(FLAG_N) && !cpu.flag(FLAG_C) && !cpu.flag(FLAG_Z));
+    cpu.step().unwrap();
+    assert!(cpu.flag(FLAG_Z) && cpu.flag(FLAG_C) && !cpu.flag(FLAG_N));
+    for _ in 0..3 {
+        cpu.step().unwrap();
+    }
+    assert_eq!(0x80, cpu.a);
+    assert!(cpu.flag(FLAG_N) && !cpu.flag(FLAG_Z));
+}
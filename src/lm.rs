@@ -0,0 +1,208 @@
+// コーパスから学習した文字n-gramの言語モデル
+//
+// 文字はパスワードの文字集合(CHAR_CODESの番号)に写し、写せない文字(空白、改行など)は文の区切りとする。
+// 英小文字は大文字として数える。確率はWitten-Bell法で短い文脈と補間する
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::cpu::Memory;
+use crate::domain::{CHAR_CODES, CODE2CHAR};
+use crate::opt::OPT;
+use crate::pruning::Validator;
+
+const ALPHABET: usize = CHAR_CODES.len();
+// 文脈に入れる文頭の記号
+const BOS: usize = ALPHABET;
+
+pub const MAX_ORDER: usize = 4;
+
+pub struct NgramModel {
+    order: usize,
+    // 直前のorder-1文字を43進数にした文脈 (新しい文字が下の桁)
+    contexts: usize,
+    // [文脈][文字] = log10 P(文字 | 文脈)
+    log_prob: Vec<[f32; ALPHABET]>,
+    // 1文字で一番高いlog10確率
+    max_log_prob: f32,
+}
+
+fn char_index(c: char) -> Option<usize> {
+    let c = c.to_ascii_uppercase();
+    let code = CODE2CHAR.iter().position(|&d| d == c)?;
+    CHAR_CODES.iter().position(|&d| d as usize == code)
+}
+
+impl NgramModel {
+    pub fn train(corpus: &str, order: usize) -> Self {
+        assert!(
+            (1..=MAX_ORDER).contains(&order),
+            "order must be between 1 and {}",
+            MAX_ORDER
+        );
+
+        // counts[k][長さkの文脈] = 次の文字ごとの出現回数
+        let mut counts: Vec<HashMap<usize, [u32; ALPHABET]>> = vec![HashMap::new(); order];
+        for sentence in corpus.split(|c| char_index(c).is_none()) {
+            let mut history = vec![BOS; order - 1];
+            for c in sentence.chars().filter_map(char_index) {
+                for (k, counts) in counts.iter_mut().enumerate() {
+                    let context = history[history.len() - k..]
+                        .iter()
+                        .fold(0, |acc, &h| acc * (ALPHABET + 1) + h);
+                    counts.entry(context).or_insert([0; ALPHABET])[c] += 1;
+                }
+                if order > 1 {
+                    history.remove(0);
+                    history.push(c);
+                }
+            }
+        }
+
+        // 短い文脈から順に、1つ短い文脈の確率と補間する
+        let mut prob = vec![[1.0 / ALPHABET as f64; ALPHABET]];
+        let mut contexts = 1;
+        for counts in &counts {
+            let shorter = &prob;
+            let next: Vec<[f64; ALPHABET]> = (0..contexts)
+                .map(|context| {
+                    // 一番古い文字を落とした文脈
+                    let backoff = shorter[context % shorter.len()];
+                    let Some(count) = counts.get(&context) else {
+                        return backoff;
+                    };
+                    let total: u32 = count.iter().sum();
+                    let types = count.iter().filter(|&&n| n > 0).count() as f64;
+                    let mut p = [0.0; ALPHABET];
+                    for c in 0..ALPHABET {
+                        p[c] = (count[c] as f64 + types * backoff[c]) / (total as f64 + types);
+                    }
+                    p
+                })
+                .collect();
+            prob = next;
+            contexts *= ALPHABET + 1;
+        }
+
+        let log_prob: Vec<_> = prob.iter().map(|p| p.map(|p| p.log10() as f32)).collect();
+        let max_log_prob = log_prob
+            .iter()
+            .flatten()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max);
+        Self {
+            order,
+            contexts: contexts / (ALPHABET + 1),
+            log_prob,
+            max_log_prob,
+        }
+    }
+
+    pub fn from_corpus(path: &Path, order: usize) -> Self {
+        let corpus = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e));
+        Self::train(&corpus, order)
+    }
+
+    // --lm-corpusがあれば、--lm-orderで学習したもの
+    pub fn from_opt() -> Option<Arc<Self>> {
+        OPT.lm_corpus
+            .as_ref()
+            .map(|path| Arc::new(Self::from_corpus(path, OPT.lm_order)))
+    }
+
+    // 文頭の文脈
+    fn start(&self) -> usize {
+        (0..self.order - 1).fold(0, |acc, _| acc * (ALPHABET + 1) + BOS)
+    }
+
+    // 文脈にcを足し、log10 P(c | 文脈)を返す
+    fn step(&self, context: &mut usize, c: usize) -> f32 {
+        let p = self.log_prob[*context][c];
+        *context = (*context * (ALPHABET + 1) + c) % self.contexts;
+        p
+    }
+
+    pub fn max_log_prob(&self) -> f64 {
+        self.max_log_prob as f64
+    }
+
+    // パスワード全体のlog10確率
+    pub fn score(&self, password: &[usize]) -> f64 {
+        let mut context = self.start();
+        password
+            .iter()
+            .map(|&c| self.step(&mut context, c) as f64)
+            .sum()
+    }
+}
+
+// 累積のlog10確率がthresholdを下回った枝を刈る
+// 状態は上位32bitに文脈、下位32bitに累積のlog10確率(f32)
+pub struct LmPruning {
+    pub model: Arc<NgramModel>,
+    pub threshold: f64,
+}
+
+impl Validator for LmPruning {
+    fn name(&self) -> &str {
+        "lm"
+    }
+
+    fn initial_state(&self) -> u64 {
+        (self.model.start() as u64) << 32 | 0f32.to_bits() as u64
+    }
+
+    fn append(
+        &self,
+        _expected_memory: &Memory,
+        _password: &[usize],
+        _segmentation: &[usize],
+        state: u64,
+        append_word: &[usize],
+    ) -> Option<u64> {
+        let mut context = (state >> 32) as usize;
+        let mut log_prob = f32::from_bits(state as u32);
+        for &c in append_word {
+            log_prob += self.model.step(&mut context, c);
+        }
+        if (log_prob as f64) < self.threshold {
+            return None;
+        }
+        Some((context as u64) << 32 | log_prob.to_bits() as u64)
+    }
+}
+
+#[test]
+fn score_plausible_strings_higher() {
+    use crate::domain::to_charcode_indices;
+
+    let corpus = "konnichiwa. sayounara. arigatou gozaimasu. ohayou gozaimasu. konbanwa.\n\
+                  oyasuminasai. itadakimasu. gochisousama deshita. sumimasen.";
+    for order in 1..=MAX_ORDER {
+        let model = NgramModel::train(corpus, order);
+        let natural = model.score(&to_charcode_indices("KONBANWA"));
+        let noise = model.score(&to_charcode_indices("QXZJQXZV"));
+        assert!(natural > noise, "order {}: {} {}", order, natural, noise);
+
+        // 確率の和は1
+        let sum: f64 = model.log_prob[model.start()]
+            .iter()
+            .map(|&p| 10f64.powf(p as f64))
+            .sum();
+        assert!((sum - 1.0).abs() < 1e-4, "order {}: {}", order, sum);
+    }
+
+    let model = Arc::new(NgramModel::train(corpus, 3));
+    let threshold = model.score(&to_charcode_indices("KONBANWA")) - 1.0;
+    let pruning = LmPruning { model, threshold };
+    let dummy = Memory::new(8);
+    let mut state = pruning.initial_state();
+    for c in to_charcode_indices("KONBANWA") {
+        state = pruning.append(&dummy, &[], &[], state, &[c]).unwrap();
+    }
+    let noise = to_charcode_indices("QXZJQXZV");
+    assert!(pruning
+        .append(&dummy, &[], &[], pruning.initial_state(), &noise)
+        .is_none());
+}
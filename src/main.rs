@@ -1,15 +1,43 @@
-use dict::dict_search;
-use domain::EXPECTED_MEMORY_14;
+use dict::{batch_search, best_search, deepen_search, dict_search, merge};
+use opt::{Command, RomCommand, OPT};
+use target::{list_targets, selected_targets};
 
 mod bitset;
+mod cluster;
+mod combinator;
+mod composition;
+mod count;
 mod cpu;
 mod dict;
 mod domain;
+mod huc6280;
+mod lm;
+mod mangle;
 mod opt;
+mod pattern;
+mod progress;
 mod pruning;
+mod rng;
+mod rom;
+mod romaji;
+mod rules;
+mod target;
 
 fn main() {
-    // dict_search(&EXPECTED_MEMORY_8);
-    // dict_search(&EXPECTED_MEMORY_11);
-    dict_search(&EXPECTED_MEMORY_14);
+    match &OPT.command {
+        Some(Command::Crosscheck(opt)) => huc6280::crosscheck(opt),
+        Some(Command::Rom(RomCommand::Targets(opt))) => rom::targets(opt),
+        Some(Command::Rom(RomCommand::Text(opt))) => rom::text(opt),
+        Some(Command::Targets) => list_targets(),
+        Some(Command::Search(opt)) => batch_search(opt),
+        Some(Command::Sample(opt)) => count::sample(opt),
+        Some(Command::Lex(command)) => count::lex(command),
+        Some(Command::Merge(opt)) => merge(opt),
+        Some(Command::Best(opt)) => best_search(opt, &selected_targets()),
+        Some(Command::Combine(opt)) => combinator::combinator_search(opt),
+        Some(Command::Deepen(opt)) => deepen_search(opt, &selected_targets()),
+        Some(Command::Coordinator(opt)) => cluster::coordinator(opt),
+        Some(Command::Worker(opt)) => cluster::worker(opt),
+        None => dict_search(&selected_targets()),
+    }
 }
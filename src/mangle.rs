@@ -0,0 +1,222 @@
+// hashcatやJohn the Ripperのルールのように、辞書の単語を書き換えて候補の単語を増やす
+//
+// ルールファイルは1行に1つのルールで、#で始まる行と空行は読み飛ばす
+// ルールは次の関数の並び。関数の間の空白は無視する
+//   :    何もしない
+//   $X   末尾にXを足す
+//   ^X   先頭にXを足す
+//   d    単語を2回繰り返す
+//   r    逆順にする
+//   f    逆順にしたものを後ろに足す
+//   [    先頭の1文字を消す
+//   ]    末尾の1文字を消す
+//   sXY  XをすべてYにする
+//   @X   Xをすべて消す
+// 文字はパスワードに使える文字(CHAR_CODES)だけ。ルールは上から順にr1, r2, ...と呼ぶ
+use std::collections::HashSet;
+
+use crate::dict::{Dict, WordInfo};
+use crate::domain::{try_to_charcode_indices, MAX_PASSWORD_LEN};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Noop,
+    Append(usize),
+    Prepend(usize),
+    Duplicate,
+    Reverse,
+    Reflect,
+    DeleteFirst,
+    DeleteLast,
+    Substitute(usize, usize),
+    Purge(usize),
+}
+
+#[derive(Debug)]
+pub struct MangleRule {
+    pub name: String,
+    // ファイルに書かれたとおりのルール
+    pub text: String,
+    ops: Vec<Op>,
+}
+
+fn parse_char(c: Option<char>, op: char) -> Result<usize, String> {
+    let c = c.ok_or_else(|| format!("{} needs a character", op))?;
+    match try_to_charcode_indices(&c.to_string()).as_deref() {
+        Some(&[i]) => Ok(i),
+        _ => Err(format!("{} is not a password character", c)),
+    }
+}
+
+fn parse_rule(name: String, text: &str) -> Result<MangleRule, String> {
+    let mut ops = Vec::new();
+    let mut chars = text.chars().filter(|c| !c.is_whitespace());
+    while let Some(op) = chars.next() {
+        ops.push(match op {
+            ':' => Op::Noop,
+            '$' => Op::Append(parse_char(chars.next(), op)?),
+            '^' => Op::Prepend(parse_char(chars.next(), op)?),
+            'd' => Op::Duplicate,
+            'r' => Op::Reverse,
+            'f' => Op::Reflect,
+            '[' => Op::DeleteFirst,
+            ']' => Op::DeleteLast,
+            's' => Op::Substitute(parse_char(chars.next(), op)?, parse_char(chars.next(), op)?),
+            '@' => Op::Purge(parse_char(chars.next(), op)?),
+            _ => return Err(format!("unknown function {}", op)),
+        });
+    }
+    Ok(MangleRule {
+        name,
+        text: text.trim().to_string(),
+        ops,
+    })
+}
+
+pub fn parse_mangle_rules(s: &str) -> Result<Vec<MangleRule>, String> {
+    let mut rules = Vec::new();
+    for (line_no, line) in s.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let name = format!("r{}", rules.len() + 1);
+        let rule = parse_rule(name, line).map_err(|e| format!("{}: {}", line_no + 1, e))?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+impl MangleRule {
+    // 書き換えた単語。空になったり、パスワードより長くなったりすればNone
+    pub fn apply(&self, word: &[usize]) -> Option<Vec<usize>> {
+        let mut word = word.to_vec();
+        for &op in &self.ops {
+            match op {
+                Op::Noop => {}
+                Op::Append(c) => word.push(c),
+                Op::Prepend(c) => word.insert(0, c),
+                Op::Duplicate => word.extend(word.clone()),
+                Op::Reverse => word.reverse(),
+                Op::Reflect => word.extend(word.clone().into_iter().rev()),
+                Op::DeleteFirst => {
+                    if !word.is_empty() {
+                        word.remove(0);
+                    }
+                }
+                Op::DeleteLast => {
+                    word.pop();
+                }
+                Op::Substitute(from, to) => {
+                    for c in &mut word {
+                        if *c == from {
+                            *c = to;
+                        }
+                    }
+                }
+                Op::Purge(c) => word.retain(|&d| d != c),
+            }
+            if word.len() > MAX_PASSWORD_LEN {
+                return None;
+            }
+        }
+        (!word.is_empty()).then_some(word)
+    }
+}
+
+// 書き換えた単語の元
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    // 元の単語の番号
+    pub base: usize,
+    pub rule: String,
+}
+
+// 辞書の2文字以上の単語にルールを当て、まだない単語を足す。重みとタグは元の単語のものを引き継ぐ
+// 1文字の単語は並べれば同じものができるので書き換えない。足した数を返す
+pub fn mangle(dict: &mut Dict, rules: &[MangleRule]) -> usize {
+    let mut known: HashSet<_> = dict.words.iter().cloned().collect();
+    let bases = dict.words.len();
+    for base in 0..bases {
+        if dict.words[base].len() < 2 || dict.info[base].origin.is_some() {
+            continue;
+        }
+        for rule in rules {
+            let Some(word) = rule.apply(&dict.words[base]) else {
+                continue;
+            };
+            if !known.insert(word.clone()) {
+                continue;
+            }
+            dict.words.push(word);
+            dict.info.push(WordInfo {
+                origin: Some(Origin {
+                    base,
+                    rule: rule.name.clone(),
+                }),
+                ..dict.info[base].clone()
+            });
+        }
+    }
+    dict.words.len() - bases
+}
+
+#[test]
+fn mangle_words_with_rules() {
+    use crate::domain::{to_charcode_indices, to_string};
+
+    let rules = parse_mangle_rules(
+        "# 数字を足す\n  # 字下げしたコメント\n$1\n$0 $3\nsO0 sI1\n\nd\n^X r\n[ ]\n",
+    )
+    .unwrap();
+    let texts: Vec<_> = rules.iter().map(|r| r.text.as_str()).collect();
+    assert_eq!(vec!["$1", "$0 $3", "sO0 sI1", "d", "^X r", "[ ]"], texts);
+    let apply = |i: usize, word: &str| {
+        rules[i]
+            .apply(&to_charcode_indices(word))
+            .map(|w| to_string(&w))
+    };
+    assert_eq!(Some("HENTAI1".to_string()), apply(0, "HENTAI"));
+    assert_eq!(Some("KAPPA03".to_string()), apply(1, "KAPPA"));
+    assert_eq!(Some("T0KY0".to_string()), apply(2, "TOKYO"));
+    assert_eq!(Some("K1T1".to_string()), apply(2, "KITI"));
+    assert_eq!(Some("KAKA".to_string()), apply(3, "KA"));
+    assert_eq!(Some("AKX".to_string()), apply(4, "KA"));
+    assert_eq!(None, apply(5, "KA"));
+    // パスワードより長くなるもの
+    assert_eq!(None, apply(3, "HENTAIOSUGI"));
+
+    assert!(parse_mangle_rules("$1\nT0\n")
+        .unwrap_err()
+        .starts_with("2:"));
+    assert!(parse_mangle_rules("$").is_err());
+    assert!(parse_mangle_rules("$?").is_err());
+
+    let words = ["A", "HENTAI", "HENTAI1"];
+    let mut dict = Dict {
+        words: words.iter().map(|w| to_charcode_indices(w)).collect(),
+        info: words
+            .iter()
+            .map(|_| WordInfo {
+                weight: 1.0,
+                tags: vec![],
+                origin: None,
+            })
+            .collect(),
+    };
+    // HENTAI1はもうあるので足さず、辞書にあったHENTAI1も書き換える
+    assert_eq!(3, mangle(&mut dict, &rules[..2]));
+    let added: Vec<_> = dict.words[3..].iter().map(|w| to_string(w)).collect();
+    assert_eq!(vec!["HENTAI03", "HENTAI11", "HENTAI103"], added);
+    assert_eq!(
+        Some(Origin {
+            base: 1,
+            rule: "r2".to_string()
+        }),
+        dict.info[3].origin
+    );
+    assert_eq!("HENTAI03=HENTAI+r2", dict.describe(3));
+    assert_eq!(
+        "HENTAI03=HENTAI+r2 A",
+        dict.describe_segmentation(&to_charcode_indices("HENTAI03A"), &[8, 1])
+    );
+}
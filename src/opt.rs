@@ -1,3 +1,8 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::Duration;
+
 use once_cell::sync::Lazy;
 use structopt::StructOpt;
 
@@ -5,22 +10,393 @@ use crate::domain::to_charcode_indices;
 
 #[derive(StructOpt)]
 pub struct Opt {
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     pub prefix: Option<String>,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     pub suffix: Option<String>,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     pub disable_japanese_pruning: bool,
 
-    #[structopt(long)]
+    /// 日本語として不自然なパスワードを除外する枝刈りのルール。省略時は組み込みのrules.toml
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub rules: Option<PathBuf>,
+
+    /// ローマ字の音節の並びとして読めないパスワードを除外する。--rulesを指定しなければ組み込みのルールの代わりに使う
+    #[structopt(long, global = true)]
+    pub romaji: bool,
+
+    /// 文字n-gramの言語モデルを学習するコーパス。見つかったパスワードにもっともらしさ(log10確率)を付けて並べる
+    #[structopt(long, global = true, parse(from_os_str), value_name = "PATH")]
+    pub lm_corpus: Option<PathBuf>,
+
+    /// 言語モデルのn (1から4)
+    #[structopt(long, global = true, default_value = "3", value_name = "N")]
+    pub lm_order: usize,
+
+    /// 言語モデルで先頭からのlog10確率の合計がこれを下回った枝を刈る
+    #[structopt(
+        long,
+        global = true,
+        allow_hyphen_values = true,
+        value_name = "LOGPROB"
+    )]
+    pub lm_threshold: Option<f64>,
+
+    /// 辞書の単語を書き換えるルール(hashcat風)のファイル。書き換えた単語を辞書に足す
+    #[structopt(long, global = true, parse(from_os_str), value_name = "PATH")]
+    pub mangle: Option<PathBuf>,
+
+    /// 1文字の単語をN個までしか使わない
+    #[structopt(long, global = true, value_name = "N")]
+    pub max_singles: Option<usize>,
+
+    /// 一番長い単語がN文字以上のパスワードだけを探す
+    #[structopt(long, global = true, value_name = "N")]
+    pub min_longest: Option<usize>,
+
+    /// 単語をN個までしか使わない
+    #[structopt(long, global = true, value_name = "N")]
+    pub max_words: Option<usize>,
+
+    /// 辞書でこのタグを付けた単語を1つ以上使う
+    #[structopt(long, global = true, value_name = "TAG")]
+    pub require_tag: Option<String>,
+
+    #[structopt(long, global = true)]
     pub verbose: bool,
 
-    #[structopt(long)]
+    #[structopt(long, global = true)]
     pub ignore_cache: bool,
+
+    /// 探索対象。16進8バイトか、登録簿に書かれた名前。複数指定可。省略時は14
+    #[structopt(long, global = true, number_of_values = 1)]
+    pub target: Vec<String>,
+
+    /// ターゲットの登録簿。省略時は./targets.toml
+    #[structopt(long, global = true, parse(from_os_str))]
+    pub target_file: Option<PathBuf>,
+
+    /// 探索をn個に分けたうちのi番目(0始まり)だけを探索する。結果は--shard-fileに書き出す
+    #[structopt(long, global = true, value_name = "i/n")]
+    pub shard: Option<Shard>,
+
+    /// --shardの結果を書き出すファイル。省略時はresults/shard-i-of-n.tsv (searchでは--output-dirの下)
+    #[structopt(long, global = true, parse(from_os_str), value_name = "PATH")]
+    pub shard_file: Option<PathBuf>,
+
+    /// 進捗を表示する。--progress=SECSで間隔(秒)を指定する。省略時は10秒、0なら表示しない
+    #[structopt(long, global = true, require_equals = true, value_name = "SECS")]
+    pub progress: Option<Option<u64>>,
+
+    /// ターゲットとの違いがKバイト以下のパスワードも近い候補として報告する
+    #[structopt(long, global = true, default_value = "0", value_name = "K")]
+    pub near_miss: usize,
+
+    /// 長さごとの枝刈りの統計をJSONで書き出す
+    #[structopt(long, global = true, parse(from_os_str), value_name = "PATH")]
+    pub stats_json: Option<PathBuf>,
     // #[structopt(long)]
     // pub contains: Option<String>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// ROMのチェックサムルーチンをエミュレータで実行し、forward_stepと突き合わせる
+    Crosscheck(CrosscheckOpt),
+
+    /// ROMイメージを解析する
+    Rom(RomCommand),
+
+    /// 登録簿のターゲットを検証して一覧を表示する
+    Targets,
+
+    /// 登録簿のターゲットをまとめて探索する
+    Search(SearchOpt),
+
+    /// --targetの解から一様にランダムに選ぶ
+    Sample(SampleOpt),
+
+    /// --targetの解の候補を辞書順に数える、取り出す
+    Lex(LexCommand),
+
+    /// --shardで分けて探索した結果をまとめる
+    Merge(MergeOpt),
+
+    /// もっともらしいパスワードから順に探す(言語モデルがなければ単語の少ない順)
+    Best(BestOpt),
+
+    /// 1文字の単語を0個, 1個, 2個, ...と許しながら探し、辞書の単語の多いパスワードを先に見つける
+    Deepen(DeepenOpt),
+
+    /// 左の辞書の単語と右の辞書の単語(と区切り)をつなげたパスワードを探す
+    Combine(CombineOpt),
+
+    /// ワーカーに探索の単位を配り、結果を集める
+    Coordinator(CoordinatorOpt),
+
+    /// コーディネータから探索の単位をもらって探索する
+    Worker(WorkerOpt),
+}
+
+#[derive(StructOpt)]
+pub struct CoordinatorOpt {
+    #[structopt(long, default_value = "127.0.0.1:7878")]
+    pub listen: String,
+
+    /// 先頭から何個目の単語までの選び方を1つの単位にするか
+    #[structopt(long, default_value = "2")]
+    pub depth: usize,
+
+    /// この秒数のあいだ何も送ってこないワーカーは落ちたものとみなし、持っていた単位を配り直す
+    #[structopt(long, default_value = "60", value_name = "SECS")]
+    pub timeout: u64,
+}
+
+#[derive(StructOpt)]
+pub struct WorkerOpt {
+    #[structopt(long, default_value = "127.0.0.1:7878")]
+    pub connect: String,
+}
+
+#[derive(Clone, Copy)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl std::str::FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected i/n: {}", s))?;
+        let index: usize = index.trim().parse().map_err(|e| format!("{}: {}", s, e))?;
+        let count: usize = count.trim().parse().map_err(|e| format!("{}: {}", s, e))?;
+        if index >= count {
+            return Err(format!("shard index must be less than {}: {}", count, s));
+        }
+        Ok(Self { index, count })
+    }
+}
+
+#[derive(StructOpt)]
+pub struct CombineOpt {
+    /// 左側の単語の辞書
+    #[structopt(long, parse(from_os_str), default_value = "dict.txt")]
+    pub left: PathBuf,
+
+    /// 右側の単語の辞書。省略時は--leftと同じ
+    #[structopt(long, parse(from_os_str))]
+    pub right: Option<PathBuf>,
+
+    /// 左右の間に入れる区切りの文字。どれか1文字を必ず入れる。省略時は区切らない
+    #[structopt(long, allow_hyphen_values = true, value_name = "CHARS")]
+    pub separators: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub struct DeepenOpt {
+    /// 1文字の単語をこの数まで許したら止める。省略時はパスワードの長さまで
+    #[structopt(long, value_name = "N")]
+    pub max_fillers: Option<usize>,
+}
+
+#[derive(StructOpt)]
+pub struct BestOpt {
+    /// キューに置く途中のパスワードの上限。超えたら見込みの低いものから捨てる
+    #[structopt(long, default_value = "1000000")]
+    pub beam: usize,
+
+    /// この数だけ見つけたら止める
+    #[structopt(long)]
+    pub limit: Option<usize>,
+}
+
+#[derive(StructOpt)]
+pub struct MergeOpt {
+    /// --shardで書き出した結果ファイル
+    #[structopt(parse(from_os_str))]
+    pub files: Vec<PathBuf>,
+
+    /// 省略時は標準出力
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+}
+
+// 解はハッシュ値のすべての分かっているバイトと枝刈りの条件を満たすパスワード。
+// 候補はDP2の条件(checkdigit2とsum)を満たし、辞書の単語を並べて作れるパスワードで、解とは限らない。
+// どちらもパスワードの辞書順に並べ、区切り方が複数あっても1回だけ数える。
+// 候補は状態ごとの数から直接求まるが、解は候補を先頭から確かめて数える
+#[derive(StructOpt)]
+pub enum LexCommand {
+    /// 解の数
+    CountSolutions,
+
+    /// index番目(0始まり)の解
+    NthSolution { index: u128 },
+
+    /// パスワードより前にある解の数
+    SolutionRank { password: String },
+
+    /// [begin, begin + count) 番目の解
+    SolutionRange { begin: u128, count: u128 },
+
+    /// 候補の数(解の数ではない)
+    CountCandidates,
+
+    /// index番目(0始まり)の候補
+    NthCandidate { index: u128 },
+
+    /// パスワードより前にある候補の数
+    CandidateRank { password: String },
+
+    /// [begin, begin + count) 番目の候補のうち、解になっているもの
+    CandidateRange {
+        begin: u128,
+
+        count: u128,
+
+        /// 解になっていない候補も表示する
+        #[structopt(long)]
+        all: bool,
+    },
+}
+
+#[derive(StructOpt)]
+pub enum RomCommand {
+    /// ROMイメージからパスワードのハッシュ値(Memoryと同じ並びの8バイト)を抜き出す
+    Targets(RomTargetsOpt),
+
+    /// ROMイメージの中の文字列を拾い、dict.txtの形式で書き出す
+    Text(RomTextOpt),
+}
+
+#[derive(StructOpt)]
+pub struct RomTextOpt {
+    #[structopt(parse(from_os_str))]
+    pub rom: PathBuf,
+
+    /// "XX=文字"の行を並べた文字コード表。省略時はパスワードの文字コードを使う
+    #[structopt(long, parse(from_os_str))]
+    pub table: Option<PathBuf>,
+
+    #[structopt(long, default_value = "3")]
+    pub min_len: usize,
+
+    #[structopt(long, default_value = "14")]
+    pub max_len: usize,
+
+    /// ここに含まれる単語は出力しない。複数指定可。省略時は./dict.txt
+    #[structopt(long, parse(from_os_str))]
+    pub exclude: Vec<PathBuf>,
+
+    /// 上位何件を出力するか
+    #[structopt(long)]
+    pub limit: Option<usize>,
+
+    /// 省略時は標準出力
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct RomTargetsOpt {
+    #[structopt(parse(from_os_str))]
+    pub rom: PathBuf,
+
+    /// テーブルの直前にある目印のバイト列(16進、??は任意の1バイト)
+    #[structopt(long)]
+    pub signature: Option<String>,
+
+    /// 目印の末尾からテーブル先頭までのバイト数
+    #[structopt(long, default_value = "0")]
+    pub skip: usize,
+
+    /// レコードの先頭オフセット(16進)。複数指定可
+    #[structopt(long, parse(try_from_str = parse_hex_usize))]
+    pub offset: Vec<usize>,
+
+    /// 目印、オフセットの位置から読むレコードの数
+    #[structopt(long, default_value = "1")]
+    pub count: usize,
+
+    /// 見つかったハッシュ値を登録簿の形式で書き出す
+    #[structopt(long, parse(from_os_str))]
+    pub export: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct SearchOpt {
+    /// 探索するターゲットの登録簿
+    #[structopt(long, parse(from_os_str))]
+    pub targets: PathBuf,
+
+    /// 解決済み(solutionが書かれている)ターゲットも探索する
+    #[structopt(long)]
+    pub all: bool,
+
+    /// ターゲットごとの結果と集計(summary.tsv)の出力先
+    #[structopt(long, parse(from_os_str), default_value = "results")]
+    pub output_dir: PathBuf,
+}
+
+#[derive(StructOpt)]
+pub struct SampleOpt {
+    /// 選ぶパスワードの数(重複あり)
+    #[structopt(long, default_value = "10")]
+    pub samples: usize,
+
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+
+    /// 棄却を含めた試行回数の上限
+    #[structopt(long, default_value = "1000000")]
+    pub max_attempts: usize,
+}
+
+#[derive(StructOpt)]
+pub struct CrosscheckOpt {
+    /// チェックサムルーチンのバイト列
+    #[structopt(long, parse(from_os_str))]
+    pub routine: PathBuf,
+
+    /// ルーチンを配置するアドレス(16進)
+    #[structopt(long, parse(try_from_str = parse_hex_u16))]
+    pub load_addr: u16,
+
+    /// ルーチンのエントリポイント(16進)。省略時はload_addr
+    #[structopt(long, parse(try_from_str = parse_hex_u16))]
+    pub entry: Option<u16>,
+
+    /// 事前にロードするRAMイメージ
+    #[structopt(long, parse(from_os_str))]
+    pub ram: Option<PathBuf>,
+
+    /// RAMイメージを配置するアドレス(16進)
+    #[structopt(long, parse(try_from_str = parse_hex_u16), default_value = "2000")]
+    pub ram_addr: u16,
+
+    /// 試行するランダムなパスワードの数
+    #[structopt(long, default_value = "10000")]
+    pub samples: usize,
+
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+}
+
+pub fn parse_hex_u16(s: &str) -> Result<u16, std::num::ParseIntError> {
+    let s = s.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(s, 16)
+}
+
+pub fn parse_hex_usize(s: &str) -> Result<usize, std::num::ParseIntError> {
+    let s = s.trim_start_matches("0x").trim_start_matches('$');
+    usize::from_str_radix(s, 16)
 }
 
 pub struct OptInternal {
@@ -30,18 +406,107 @@ pub struct OptInternal {
 
     pub disable_japanese_pruning: bool,
 
+    pub rules: Option<PathBuf>,
+
+    pub romaji: bool,
+
+    pub lm_corpus: Option<PathBuf>,
+
+    pub lm_order: usize,
+
+    pub lm_threshold: Option<f64>,
+
+    pub mangle: Option<PathBuf>,
+
+    pub max_singles: Option<usize>,
+
+    pub min_longest: Option<usize>,
+
+    pub max_words: Option<usize>,
+
+    pub require_tag: Option<String>,
+
     pub verbose: bool,
 
     pub ignore_cache: bool,
+
+    pub target: Vec<String>,
+
+    pub target_file: Option<PathBuf>,
+
+    pub near_miss: usize,
+
+    pub shard: Option<Shard>,
+
+    pub shard_file: Option<PathBuf>,
+
+    pub progress: Option<Duration>,
+
+    pub stats_json: Option<PathBuf>,
+
+    pub command: Option<Command>,
 }
 
 pub static OPT: Lazy<OptInternal> = Lazy::new(|| {
-    let opt = Opt::from_args();
+    // テスト実行時はテストハーネスの引数を解釈しない
+    let opt = if cfg!(test) {
+        Opt::from_iter(["decrypt"])
+    } else {
+        Opt::from_args()
+    };
     OptInternal {
         prefix: opt.prefix.as_ref().map(|s| to_charcode_indices(s)),
         suffix: opt.suffix.as_ref().map(|s| to_charcode_indices(s)),
         verbose: opt.verbose,
         disable_japanese_pruning: opt.disable_japanese_pruning,
+        rules: opt.rules,
+        romaji: opt.romaji,
+        lm_corpus: opt.lm_corpus,
+        lm_order: opt.lm_order,
+        lm_threshold: opt.lm_threshold,
+        mangle: opt.mangle,
+        max_singles: opt.max_singles,
+        min_longest: opt.min_longest,
+        max_words: opt.max_words,
+        require_tag: opt.require_tag,
         ignore_cache: opt.ignore_cache,
+        target: opt.target,
+        target_file: opt.target_file,
+        near_miss: opt.near_miss,
+        shard: opt.shard,
+        shard_file: opt.shard_file,
+        progress: opt
+            .progress
+            .map(|secs| secs.unwrap_or(10))
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        stats_json: opt.stats_json,
+        command: opt.command,
     }
 });
+
+impl OptInternal {
+    // 探索の結果を変えるオプションのハッシュ。ファイルは中身で比べる
+    pub fn search_hash(&self) -> u64 {
+        let file = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|p| std::fs::read(p).unwrap_or_else(|e| panic!("{}: {}", p.display(), e)))
+        };
+        let mut hasher = DefaultHasher::new();
+        self.prefix.hash(&mut hasher);
+        self.suffix.hash(&mut hasher);
+        self.disable_japanese_pruning.hash(&mut hasher);
+        file(&self.rules).hash(&mut hasher);
+        self.romaji.hash(&mut hasher);
+        file(&self.lm_corpus).hash(&mut hasher);
+        self.lm_order.hash(&mut hasher);
+        self.lm_threshold.map(f64::to_bits).hash(&mut hasher);
+        file(&self.mangle).hash(&mut hasher);
+        self.max_singles.hash(&mut hasher);
+        self.min_longest.hash(&mut hasher);
+        self.max_words.hash(&mut hasher);
+        self.require_tag.hash(&mut hasher);
+        self.near_miss.hash(&mut hasher);
+        hasher.finish()
+    }
+}
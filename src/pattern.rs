@@ -0,0 +1,357 @@
+// 探索の枝刈りに使う表
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use rayon::prelude::IntoParallelRefIterator;
+
+use crate::bitset::BitSet256;
+use crate::cpu::{forward_word, Memory, PartialMemory};
+use crate::dict::Dict;
+use crate::domain::CHAR_CODES;
+use crate::opt::OPT;
+use crate::pruning::satisfy_option_constraint;
+
+// [len][bit][sum][xor]
+pub type Pattern1 = Vec<Vec<Vec<Vec<bool>>>>;
+
+// [len][checkdigit2[0]][checkdigit2[1]] の checkdigit5[0] の集合
+pub type Pattern2 = Vec<Vec<Vec<BitSet256>>>;
+
+// 先頭から到達できる状態。Pattern2と同じ並び
+pub type Reachable = Vec<Vec<Vec<BitSet256>>>;
+
+// 単語の表を持つ2文字以上の単語の数。表は1つ192KBなので、これで96MBになる
+const MAX_WORD_TABLES: usize = 512;
+
+// (checkdigit2[0], checkdigit2[1]) から文字や単語を入力したあとの
+// (checkdigit2[0], checkdigit2[1], checkdigit5[0]の増分)
+// checkdigit5[0]の増分はcheckdigit5[0]自身の値によらないので、ターゲットが変わっても使い回せる。
+// 表は文字ごとに持ち、2文字以上の単語は先頭からMAX_WORD_TABLES個まで単語ごとの表も持つ。
+// --mangleで足した単語は辞書の後ろに並ぶので、多すぎる分は文字の表を順に引いて求める
+pub struct Transitions {
+    chars: Vec<Vec<[u8; 3]>>,
+    words: Vec<Vec<usize>>,
+    tables: Vec<Option<Vec<[u8; 3]>>>,
+}
+
+impl Transitions {
+    pub fn new(dict: &Dict) -> Self {
+        eprintln!("calc transitions");
+
+        let mut used = vec![false; CHAR_CODES.len()];
+        for &c in dict.words.iter().flatten() {
+            used[c] = true;
+        }
+        let chars = used
+            .par_iter()
+            .enumerate()
+            .map(|(c, &used)| {
+                if !used {
+                    return Vec::new();
+                }
+                let mut table = vec![[0; 3]; 0x10000];
+                for (i, t) in table.iter_mut().enumerate() {
+                    let mut memory = Memory {
+                        checkdigit2: [(i >> 8) as u8, i as u8],
+                        password_len: 0,
+                        checkdigit5: [0, 0, 0, 0, 0],
+                    };
+                    forward_word(&mut memory, &[c]);
+                    *t = [
+                        memory.checkdigit2[0],
+                        memory.checkdigit2[1],
+                        memory.checkdigit5[0],
+                    ];
+                }
+                table
+            })
+            .collect();
+        let mut transitions = Self {
+            chars,
+            words: dict.words.clone(),
+            tables: Vec::new(),
+        };
+
+        let mut remaining = MAX_WORD_TABLES;
+        let with_table: Vec<bool> = dict
+            .words
+            .iter()
+            .map(|word| {
+                let with_table = word.len() > 1 && remaining > 0;
+                remaining -= with_table as usize;
+                with_table
+            })
+            .collect();
+        transitions.tables = with_table
+            .par_iter()
+            .enumerate()
+            .map(|(w, &with_table)| {
+                with_table.then(|| {
+                    (0..0x10000)
+                        .map(|i| {
+                            let (s0, s1, offset) = transitions.get(w, i >> 8, i & 0xFF);
+                            [s0 as u8, s1 as u8, offset as u8]
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        if dict.words.iter().filter(|w| w.len() > 1).count() > MAX_WORD_TABLES {
+            eprintln!(
+                "only the first {} words with 2 or more characters have their own transition tables",
+                MAX_WORD_TABLES
+            );
+        }
+
+        transitions
+    }
+
+    pub fn get(&self, word: usize, s0: usize, s1: usize) -> (usize, usize, usize) {
+        // 作っている途中はtablesが空なので、文字の表から求める
+        if let Some(Some(table)) = self.tables.get(word) {
+            let [s0, s1, offset] = table[s0 << 8 | s1];
+            return (s0 as usize, s1 as usize, offset as usize);
+        }
+
+        let (mut s0, mut s1, mut offset) = (s0, s1, 0u8);
+        for &c in &self.words[word] {
+            let [t0, t1, t2] = self.chars[c][s0 << 8 | s1];
+            s0 = t0 as usize;
+            s1 = t1 as usize;
+            offset = offset.wrapping_add(t2);
+        }
+        (s0, s1, offset as usize)
+    }
+}
+
+pub fn build_pattern1(dict: &Dict, target: &PartialMemory) -> Pattern1 {
+    eprintln!("calc DP1");
+
+    let len = target.len();
+    let expected_memory = &Memory::new(len as u8);
+
+    let mut pattern = vec![vec![vec![vec![false; 0x100]; 0x100]; target.max_bit() + 1]; len + 1];
+
+    // 分からないバイトはどの値でもよい
+    for bit in target.bit() {
+        for &sum in &target.sum() {
+            for &xor in &target.xor() {
+                pattern[len][bit][sum][xor] = true;
+            }
+        }
+    }
+
+    for len in (0..pattern.len()).rev() {
+        for bit in 0..pattern[len].len() {
+            for sum in 0..0x100 {
+                for xor in 0..0x100 {
+                    if !pattern[len][bit][sum][xor] {
+                        continue;
+                    }
+
+                    for word in &dict.words {
+                        if len < word.len() {
+                            continue;
+                        }
+
+                        let len = len - word.len();
+                        if !satisfy_option_constraint(expected_memory, len, word) {
+                            continue;
+                        }
+
+                        let mut bit = bit;
+                        let mut sum = sum;
+                        let mut xor = xor;
+
+                        let mut dbit = 0;
+                        for &i in word {
+                            let c = CHAR_CODES[i] as usize;
+                            dbit += c.count_ones() as usize;
+                            sum = (sum + 0x100 - c) & 0xFF;
+                            xor ^= c;
+                        }
+                        if bit < dbit {
+                            continue;
+                        }
+                        bit -= dbit;
+                        pattern[len][bit][sum][xor] = true;
+                        if bit > 1 {
+                            pattern[len][bit - 1][sum][xor] = true;
+                            pattern[len][bit - 1][(sum + 0xFF) & 0xFF][xor] = true;
+                        }
+                        pattern[len][bit][(sum + 0xFF) & 0xFF][xor] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    pattern
+}
+
+// 先頭から到達できる状態を求める
+// ターゲットの長さにしか依存しないので、同じ長さのターゲットの間で使い回せる
+pub fn build_reachable(dict: &Dict, transitions: &Transitions, len: usize) -> Reachable {
+    eprintln!("calc reachable states (len {})", len);
+
+    let mut visited = vec![vec![vec![BitSet256::default(); 0x100]; 0x100]; len + 1];
+    {
+        let memory = Memory::new(len as u8);
+        let s0 = memory.checkdigit2[0] as usize;
+        let s1 = memory.checkdigit2[1] as usize;
+        let s2 = memory.checkdigit5[0] as usize;
+        visited[0][s0][s1].flip(s2);
+    }
+
+    // satisfy_option_constraintは長さしか見ない
+    let dummy = Memory::new(len as u8);
+
+    for l in 0..len {
+        for s0 in 0..0x100 {
+            for s1 in 0..0x100 {
+                if visited[l][s0][s1].is_empty() {
+                    continue;
+                }
+
+                for (w, word) in dict.words.iter().enumerate() {
+                    if l + word.len() > len {
+                        continue;
+                    }
+
+                    if !satisfy_option_constraint(&dummy, l, word) {
+                        continue;
+                    }
+
+                    let (next_s0, next_s1, offset) = transitions.get(w, s0, s1);
+                    let rotated = visited[l][s0][s1].rot_left(offset);
+                    visited[l + word.len()][next_s0][next_s1] |= rotated;
+                }
+            }
+        }
+    }
+
+    visited
+}
+
+pub fn build_pattern2(
+    dict: &Dict,
+    transitions: &Transitions,
+    reachable: &Reachable,
+    target: &PartialMemory,
+) -> Pattern2 {
+    eprintln!("calc DP2");
+
+    // テストではリポジトリのcacheに残った古い表を信じず、書き出しもしない
+    let use_cache = !cfg!(test);
+    let mut hasher = DefaultHasher::new();
+    dict.hash(&mut hasher);
+    OPT.prefix.hash(&mut hasher);
+    OPT.suffix.hash(&mut hasher);
+    target.hash(&mut hasher);
+    let hash = hasher.finish();
+    let cache_path = format!("cache/pattern2_{:016x}.bin", hash);
+
+    if use_cache && !OPT.ignore_cache {
+        if let Ok(mut f) = std::fs::File::open(&cache_path) {
+            let mut pattern = Vec::new();
+            f.read_to_end(&mut pattern).unwrap();
+            return bincode::deserialize(&pattern[..]).unwrap();
+        }
+    }
+
+    let len = target.len();
+    let expected_memory = &Memory::new(len as u8);
+
+    let mut dp = vec![vec![vec![BitSet256::default(); 0x100]; 0x100]; len + 1];
+
+    {
+        let [s0s, s1s] = target.checkdigit2();
+        for &s0 in &s0s {
+            for &s1 in &s1s {
+                for s2 in target.sum() {
+                    dp[len][s0][s1].set(s2);
+                }
+            }
+        }
+    }
+
+    // 後ろの長さから順に確定させていけば、1パスで済む
+    for l in (0..len).rev() {
+        let (current, next) = dp.split_at_mut(l + 1);
+        let current = &mut current[l];
+
+        current.par_iter_mut().enumerate().for_each(|(s0, dp_s0)| {
+            for (s1, dp_s1) in dp_s0.iter_mut().enumerate() {
+                let visited = &reachable[l][s0][s1];
+                if visited.is_empty() {
+                    continue;
+                }
+
+                for (w, word) in dict.words.iter().enumerate() {
+                    if l + word.len() > len {
+                        continue;
+                    }
+
+                    if !satisfy_option_constraint(expected_memory, l, word) {
+                        continue;
+                    }
+
+                    let (next_s0, next_s1, offset) = transitions.get(w, s0, s1);
+                    let rotated = next[word.len() - 1][next_s0][next_s1].rot_right(offset);
+                    *dp_s1 |= &rotated & visited;
+                }
+            }
+        });
+    }
+
+    if use_cache {
+        std::fs::create_dir_all("cache").unwrap();
+        std::fs::write(&cache_path, bincode::serialize(&dp).unwrap()).unwrap();
+    }
+    dp
+}
+
+#[test]
+fn transitions_match_forward_word() {
+    use crate::dict::WordInfo;
+    use crate::domain::to_charcode_indices;
+
+    let words = ["A", "HENTAI", "818"];
+    let dict = Dict {
+        words: words.iter().map(|w| to_charcode_indices(w)).collect(),
+        info: words
+            .iter()
+            .map(|_| WordInfo {
+                weight: 1.0,
+                tags: vec![],
+                origin: None,
+            })
+            .collect(),
+    };
+    let mut transitions = Transitions::new(&dict);
+    let check = |transitions: &Transitions| {
+        for (w, word) in dict.words.iter().enumerate() {
+            for (s0, s1) in [(0, 0), (0x12, 0x34), (0xE5, 0xFF), (0xFF, 0x01)] {
+                let mut memory = Memory {
+                    checkdigit2: [s0 as u8, s1 as u8],
+                    password_len: 0,
+                    checkdigit5: [0, 0, 0, 0, 0],
+                };
+                forward_word(&mut memory, word);
+                let expected = (
+                    memory.checkdigit2[0] as usize,
+                    memory.checkdigit2[1] as usize,
+                    memory.checkdigit5[0] as usize,
+                );
+                assert_eq!(expected, transitions.get(w, s0, s1));
+            }
+        }
+    };
+    check(&transitions);
+    // 単語の表を持たない単語は文字の表を順に引く
+    assert!(transitions.tables[1].is_some());
+    transitions.tables = vec![None; dict.words.len()];
+    check(&transitions);
+}
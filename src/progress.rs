@@ -0,0 +1,239 @@
+// 探索中の統計と、定期的な進捗表示
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::domain::MAX_PASSWORD_LEN;
+
+// 以下の[長さ]は、そのノードまでのパスワードの長さ
+type PerDepth = [AtomicU64; MAX_PASSWORD_LEN + 1];
+
+#[derive(Default)]
+pub struct Stats {
+    pub nodes: PerDepth,
+    // DP1, DP2の表で打ち切ったノードの数。どちらでも打ち切れるときはDP1に数える
+    pub pattern1_cuts: PerDepth,
+    pub pattern2_cuts: PerDepth,
+    // [バリデータ]ごとに、その長さのパスワードに単語を足そうとして弾いた数
+    pub rejects: Vec<PerDepth>,
+    pub found: AtomicU64,
+    // 探索し終えた単位(先頭の単語の選び方)の数
+    pub units_done: AtomicUsize,
+}
+
+fn total(counts: &PerDepth) -> u64 {
+    counts.iter().map(|c| c.load(Ordering::Relaxed)).sum()
+}
+
+impl Stats {
+    pub fn new(validators: usize) -> Self {
+        Self {
+            rejects: (0..validators).map(|_| PerDepth::default()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn table(&self, length: usize, validators: &[&str]) -> StatsTable {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let depths = (0..=length)
+            .map(|depth| DepthStats {
+                depth,
+                nodes: load(&self.nodes[depth]),
+                pattern1_cuts: load(&self.pattern1_cuts[depth]),
+                pattern2_cuts: load(&self.pattern2_cuts[depth]),
+                rejects: self.rejects.iter().map(|r| load(&r[depth])).collect(),
+            })
+            .collect();
+        StatsTable {
+            length,
+            validators: validators.iter().map(|s| s.to_string()).collect(),
+            depths,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct StatsTable {
+    pub length: usize,
+    pub validators: Vec<String>,
+    pub depths: Vec<DepthStats>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct DepthStats {
+    pub depth: usize,
+    pub nodes: u64,
+    pub pattern1_cuts: u64,
+    pub pattern2_cuts: u64,
+    // validatorsと同じ順
+    pub rejects: Vec<u64>,
+}
+
+impl StatsTable {
+    // 長さごとの行と合計の行。バリデータの列は弾いた数
+    pub fn to_text(&self) -> String {
+        let mut header = vec![
+            "depth".to_string(),
+            "nodes".to_string(),
+            "DP1".to_string(),
+            "DP2".to_string(),
+        ];
+        header.extend(self.validators.iter().cloned());
+
+        let row = |d: &DepthStats| {
+            let mut row = vec![d.nodes, d.pattern1_cuts, d.pattern2_cuts];
+            row.extend(&d.rejects);
+            row
+        };
+        let mut rows: Vec<_> = self
+            .depths
+            .iter()
+            .filter(|d| row(d).iter().any(|&n| n > 0))
+            .map(|d| {
+                let mut cells = vec![d.depth.to_string()];
+                cells.extend(row(d).iter().map(|n| n.to_string()));
+                cells
+            })
+            .collect();
+        let mut totals = vec!["total".to_string()];
+        for i in 0..header.len() - 1 {
+            let sum: u64 = self.depths.iter().map(|d| row(d)[i]).sum();
+            totals.push(sum.to_string());
+        }
+        rows.push(totals);
+
+        let widths: Vec<_> = (0..header.len())
+            .map(|i| {
+                rows.iter()
+                    .map(|r| r[i].len())
+                    .chain(std::iter::once(header[i].len()))
+                    .max()
+                    .unwrap()
+            })
+            .collect();
+        let mut s = String::new();
+        for r in std::iter::once(&header).chain(&rows) {
+            let cells: Vec<_> = r
+                .iter()
+                .zip(&widths)
+                .map(|(c, &w)| format!("{:>w$}", c, w = w))
+                .collect();
+            s += &cells.join(" ");
+            s += "\n";
+        }
+        s
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+fn format_count(n: f64) -> String {
+    if n >= 1e9 {
+        format!("{:.1}G", n / 1e9)
+    } else if n >= 1e6 {
+        format!("{:.1}M", n / 1e6)
+    } else if n >= 1e3 {
+        format!("{:.1}k", n / 1e3)
+    } else {
+        format!("{:.0}", n)
+    }
+}
+
+pub fn progress_line(stats: &Stats, units_total: usize, elapsed: Duration) -> String {
+    let done = stats.units_done.load(Ordering::Relaxed);
+    let nodes = total(&stats.nodes);
+    let percent = |n: u64| 100.0 * n as f64 / nodes.max(1) as f64;
+    let secs = elapsed.as_secs_f64();
+
+    // 終わった単位にかかった時間から、残りの単位にかかる時間を見積もる
+    let eta = if done == 0 {
+        "?".to_string()
+    } else {
+        format_duration(secs / done as f64 * (units_total - done) as f64)
+    };
+
+    format!(
+        "progress: {}/{} branches, {} nodes ({}/s), cut DP1 {:.1}% DP2 {:.1}%, {} found, elapsed {}, ETA {}",
+        done,
+        units_total,
+        format_count(nodes as f64),
+        format_count(nodes as f64 / secs.max(1e-3)),
+        percent(total(&stats.pattern1_cuts)),
+        percent(total(&stats.pattern2_cuts)),
+        stats.found.load(Ordering::Relaxed),
+        format_duration(secs),
+        eta
+    )
+}
+
+// すべての単位が終わるまで、interval秒ごとに進捗を表示する
+// 端末なら同じ行を書き換え、ファイルにリダイレクトされていれば1行ずつ書く
+pub fn report_progress(stats: &Stats, units_total: usize, start: Instant, interval: Duration) {
+    let tty = std::io::stderr().is_terminal();
+    let finished = || stats.units_done.load(Ordering::Relaxed) >= units_total;
+
+    let mut printed = false;
+    while !finished() {
+        let next = Instant::now() + interval;
+        while !finished() && Instant::now() < next {
+            std::thread::sleep(Duration::from_millis(100).min(interval));
+        }
+        if finished() {
+            break;
+        }
+
+        let line = progress_line(stats, units_total, start.elapsed());
+        let mut stderr = std::io::stderr().lock();
+        if tty {
+            write!(stderr, "\r\x1b[K{}", line).unwrap();
+            stderr.flush().unwrap();
+        } else {
+            writeln!(stderr, "{}", line).unwrap();
+        }
+        printed = true;
+    }
+
+    if tty && printed {
+        eprintln!();
+    }
+}
+
+#[test]
+fn estimate_remaining_time() {
+    let stats = Stats::default();
+    stats.units_done.store(10, Ordering::Relaxed);
+    stats.nodes[3].store(2000, Ordering::Relaxed);
+    stats.pattern1_cuts[3].store(500, Ordering::Relaxed);
+    let line = progress_line(&stats, 40, Duration::from_secs(100));
+    assert!(line.contains("10/40 branches"), "{}", line);
+    assert!(line.contains("cut DP1 25.0%"), "{}", line);
+    assert!(line.ends_with("ETA 5m00s"), "{}", line);
+}
+
+#[test]
+fn stats_table_totals() {
+    let stats = Stats::new(2);
+    stats.nodes[0].store(1, Ordering::Relaxed);
+    stats.nodes[2].store(10, Ordering::Relaxed);
+    stats.rejects[1][2].store(7, Ordering::Relaxed);
+    let table = stats.table(3, &["a", "b"]);
+    let text = table.to_text();
+    let lines: Vec<_> = text.lines().collect();
+    // ヘッダ、長さ0と2の行、合計
+    assert_eq!(4, lines.len(), "{}", text);
+    assert!(lines[3]
+        .split_whitespace()
+        .eq(["total", "11", "0", "0", "0", "7"]));
+    assert_eq!(Some(&7), table.depths[2].rejects.get(1));
+}
@@ -1,151 +1,275 @@
-use once_cell::sync::Lazy;
-
 use crate::cpu::Memory;
 use crate::opt::OPT;
 
-use crate::domain::{is_alpha, is_number, is_symbol, is_vowel};
+use crate::domain::{is_number, is_symbol};
+use crate::romaji::Romaji;
+use crate::rules::{parse_rules, Rule, DEFAULT_RULES};
 
-type Validator = fn(&Memory, &[usize], &[usize]) -> bool;
+// 単語を足すたびに呼ばれる枝刈りの条件
+// 状態はノードごとに1つのu64で、DFSで子のノードに渡していく。
+// パスワード全体を見直さなくても、前の状態から足した単語の分だけ更新すればよい
+pub trait Validator: Send + Sync {
+    // 統計に出す名前
+    fn name(&self) -> &str;
 
-static VALIDATORS: Lazy<Vec<Validator>> = Lazy::new(|| {
-    let mut validators: Vec<Validator> = vec![
-        validate_option,
-        validate_first_char_is_symbol,
-        validate_consecutive_symbols,
-        validate_suffix_consecutive_digits_length,
-    ];
-    if !OPT.disable_japanese_pruning {
-        validators.push(validate_natural_japanese);
+    // 空のパスワードの状態
+    fn initial_state(&self) -> u64 {
+        0
     }
-    validators
-});
 
-// オプションによるvalidation
-pub fn satisfy_option_constraint(expected_memory: &Memory, index: usize, word: &[usize]) -> bool {
-    if let Some(prefix) = &OPT.prefix {
-        if index < prefix.len() {
-            let n = (prefix.len() - index).min(word.len());
-            if prefix[index..index + n] != word[0..n] {
-                return false;
-            }
-        }
+    // passwordにappend_wordを足してよければ、足したあとの状態
+    // segmentationはpasswordを区切った単語の長さ。単語の境目で条件を変えるのに使う
+    fn append(
+        &self,
+        expected_memory: &Memory,
+        password: &[usize],
+        segmentation: &[usize],
+        state: u64,
+        append_word: &[usize],
+    ) -> Option<u64>;
+}
+
+// 状態を持たないバリデータを関数で書くためのもの
+pub struct FnValidator<F> {
+    pub name: String,
+    pub check: F,
+}
+
+impl<F> Validator for FnValidator<F>
+where
+    F: Fn(&Memory, &[usize], &[usize]) -> bool + Send + Sync,
+{
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    if let Some(suffix) = &OPT.suffix {
-        let i = index.max(expected_memory.len() - suffix.len());
-        let j = index + word.len();
-        if i < j {
-            let o = expected_memory.len() - suffix.len();
-            if suffix[i - o..j - o] != word[i - index..] {
-                return false;
-            }
-        }
+    fn append(
+        &self,
+        expected_memory: &Memory,
+        password: &[usize],
+        _segmentation: &[usize],
+        _state: u64,
+        append_word: &[usize],
+    ) -> Option<u64> {
+        (self.check)(expected_memory, password, append_word).then_some(0)
     }
+}
 
-    true
+fn stateless(name: &str, check: fn(&Memory, &[usize], &[usize]) -> bool) -> Box<dyn Validator> {
+    Box::new(FnValidator {
+        name: name.to_string(),
+        check,
+    })
 }
 
-// オプションによるvalidation
-fn validate_option(expected_memory: &Memory, password: &[usize], append_word: &[usize]) -> bool {
-    satisfy_option_constraint(expected_memory, password.len(), append_word)
+// 登録できるバリデータの数の上限
+pub const MAX_VALIDATORS: usize = 32;
+
+// バリデータごとの状態。DFSの枝ごとにVecを確保しないよう、固定長の配列で持ってコピーする
+#[derive(Clone, Copy)]
+pub struct States {
+    len: usize,
+    states: [u64; MAX_VALIDATORS],
 }
 
-/// 日本語として自然な言葉かどうかを検証する
-fn validate_natural_japanese(
-    _expected_memory: &Memory,
-    password: &[usize],
-    append_word: &[usize],
-) -> bool {
-    fn non_vowel_before_symbol(password_last_char: usize, append_word_first_char: usize) -> bool {
-        !is_vowel(password_last_char) && is_symbol(append_word_first_char)
-    }
+impl std::ops::Deref for States {
+    type Target = [u64];
 
-    fn non_vowel_before_number(password_last_char: usize, append_word_first_char: usize) -> bool {
-        !is_vowel(password_last_char) && is_number(append_word_first_char)
+    fn deref(&self) -> &[u64] {
+        &self.states[..self.len]
     }
+}
 
-    fn consecutive_same_char(password_last_char: usize, append_word_first_char: usize) -> bool {
-        password_last_char == append_word_first_char
+impl std::ops::DerefMut for States {
+    fn deref_mut(&mut self) -> &mut [u64] {
+        &mut self.states[..self.len]
     }
+}
 
-    fn consecutive_vowel(password: &[usize], append_word: &[usize]) -> bool {
-        if password.len() < 3 {
-            return false;
+// 上から順に試すバリデータの並び
+#[derive(Default)]
+pub struct Validators(Vec<Box<dyn Validator>>);
+
+impl Validators {
+    // 組み込みの条件と、--rules(省略時はrules.toml)のルール、--romajiのオートマトン
+    pub fn new() -> Self {
+        let mut validators = Self(vec![
+            stateless("option", validate_option),
+            stateless("first_char_is_symbol", validate_first_char_is_symbol),
+            stateless("consecutive_symbols", validate_consecutive_symbols),
+            Box::new(SuffixDigitsLength { max: 4 }),
+        ]);
+        if !OPT.disable_japanese_pruning {
+            // --romajiのときは、--rulesを指定しなければ組み込みのルールの代わりにオートマトンを使う
+            if !OPT.romaji || OPT.rules.is_some() {
+                for rule in load_rules() {
+                    validators.register(Box::new(rule));
+                }
+            }
+            if OPT.romaji {
+                validators.register(Box::new(Romaji::new()));
+            }
         }
+        validators
+    }
 
-        let c0 = password[password.len() - 3];
-        let c1 = password[password.len() - 2];
-        let c2 = password[password.len() - 1];
-        let c3 = append_word[0];
-
-        // 母音が4回以上連続するケースを除外
-        is_vowel(c0) && is_vowel(c1) && is_vowel(c2) && is_vowel(c3)
+    // 最後に試すバリデータとして足す
+    pub fn register(&mut self, validator: Box<dyn Validator>) {
+        assert!(
+            self.0.len() < MAX_VALIDATORS,
+            "too many validators (up to {})",
+            MAX_VALIDATORS
+        );
+        self.0.push(validator);
     }
 
-    // TODO これはオフったほうがいい？
-    fn consecutive_non_vowel(password: &[usize], append_word: &[usize]) -> bool {
-        if password.len() < 2 {
-            return false;
+    // 同じ名前のバリデータがあれば同じ位置で差し替え、なければ最後に足す。差し替えたものを返す
+    pub fn replace(&mut self, validator: Box<dyn Validator>) -> Option<Box<dyn Validator>> {
+        match self.0.iter().position(|v| v.name() == validator.name()) {
+            Some(i) => Some(std::mem::replace(&mut self.0[i], validator)),
+            None => {
+                self.register(validator);
+                None
+            }
         }
-
-        let c0 = password[password.len() - 2];
-        let c1 = password[password.len() - 1];
-        let c2 = append_word[0];
-        !is_vowel(c0) && !is_vowel(c1) && !is_vowel(c2)
     }
 
-    if password.is_empty() {
-        return true;
+    pub fn remove(&mut self, name: &str) -> Option<Box<dyn Validator>> {
+        let i = self.0.iter().position(|v| v.name() == name)?;
+        Some(self.0.remove(i))
     }
 
-    let password_last_char = password[password.len() - 1];
-    let append_word_first_char = append_word[0];
+    pub fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|v| v.name()).collect()
+    }
 
-    if is_alpha(password_last_char) {
-        if non_vowel_before_symbol(password_last_char, append_word_first_char) {
-            return false;
+    pub fn initial_states(&self) -> States {
+        let mut states = States {
+            len: self.0.len(),
+            states: [0; MAX_VALIDATORS],
+        };
+        for (state, v) in states.iter_mut().zip(&self.0) {
+            *state = v.initial_state();
         }
+        states
+    }
 
-        if non_vowel_before_number(password_last_char, append_word_first_char) {
-            return false;
+    // 足したあとの状態。弾かれたら、最初に弾いたバリデータの番号
+    // 前のバリデータで弾かれたものは後ろのバリデータには数えない
+    pub fn append(
+        &self,
+        expected_memory: &Memory,
+        password: &[usize],
+        segmentation: &[usize],
+        states: &States,
+        append_word: &[usize],
+    ) -> Result<States, usize> {
+        let mut next = *states;
+        for (i, (v, state)) in self.0.iter().zip(next.iter_mut()).enumerate() {
+            *state = v
+                .append(expected_memory, password, segmentation, *state, append_word)
+                .ok_or(i)?;
         }
+        Ok(next)
     }
 
-    // アルファベットでなければ以下のチェックはしない
-    if !is_alpha(append_word_first_char) {
-        return true;
+    // 単語の並びを先頭から足していって、どこでも弾かれないか
+    pub fn accepts<'a>(
+        &self,
+        expected_memory: &Memory,
+        words: impl IntoIterator<Item = &'a [usize]>,
+    ) -> bool {
+        let mut password = Vec::new();
+        let mut segmentation = Vec::new();
+        let mut states = self.initial_states();
+        for word in words {
+            match self.append(expected_memory, &password, &segmentation, &states, word) {
+                Ok(next) => states = next,
+                Err(_) => return false,
+            }
+            password.extend(word);
+            segmentation.push(word.len());
+        }
+        true
     }
+}
 
-    if consecutive_same_char(password_last_char, append_word_first_char) {
-        return false;
-    }
+// 日本語として自然な言葉かどうかを検証するルール。--rulesで差し替えられる
+fn load_rules() -> Vec<Rule> {
+    let (source, s) = match &OPT.rules {
+        Some(path) => (
+            path.display().to_string(),
+            std::fs::read_to_string(path)
+                .unwrap_or_else(|e| panic!("cannot read {}: {}", path.display(), e)),
+        ),
+        None => ("default rules".to_string(), DEFAULT_RULES.to_string()),
+    };
+    parse_rules(&s).unwrap_or_else(|e| panic!("{}: {}", source, e))
+}
 
-    if consecutive_non_vowel(password, append_word) {
-        return false;
+// オプションによるvalidation
+pub fn satisfy_option_constraint(expected_memory: &Memory, index: usize, word: &[usize]) -> bool {
+    if let Some(prefix) = &OPT.prefix {
+        if index < prefix.len() {
+            let n = (prefix.len() - index).min(word.len());
+            if prefix[index..index + n] != word[0..n] {
+                return false;
+            }
+        }
     }
 
-    if consecutive_vowel(password, append_word) {
-        return false;
+    if let Some(suffix) = &OPT.suffix {
+        let i = index.max(expected_memory.len() - suffix.len());
+        let j = index + word.len();
+        if i < j {
+            let o = expected_memory.len() - suffix.len();
+            if suffix[i - o..j - o] != word[i - index..] {
+                return false;
+            }
+        }
     }
 
     true
 }
 
-// 5桁以上の数値はNG
-fn validate_suffix_consecutive_digits_length(
-    _expected_memory: &Memory,
-    password: &[usize],
-    append_word: &[usize],
-) -> bool {
-    if append_word.len() == 1 && is_number(append_word[0]) {
-        let len = password.iter().rev().take_while(|&&c| is_number(c)).count() as usize;
+// オプションによるvalidation
+fn validate_option(expected_memory: &Memory, password: &[usize], append_word: &[usize]) -> bool {
+    satisfy_option_constraint(expected_memory, password.len(), append_word)
+}
 
-        if len + append_word.len() > 4 {
-            return false;
-        }
+// 5桁以上の数値はNG。状態は末尾に続いている数字の数
+struct SuffixDigitsLength {
+    max: u64,
+}
+
+impl Validator for SuffixDigitsLength {
+    fn name(&self) -> &str {
+        "suffix_consecutive_digits_length"
     }
 
-    true
+    fn append(
+        &self,
+        _expected_memory: &Memory,
+        _password: &[usize],
+        _segmentation: &[usize],
+        state: u64,
+        append_word: &[usize],
+    ) -> Option<u64> {
+        if append_word.len() == 1 && is_number(append_word[0]) && state + 1 > self.max {
+            return None;
+        }
+
+        let len = append_word
+            .iter()
+            .rev()
+            .take_while(|&&c| is_number(c))
+            .count();
+        if len == append_word.len() {
+            Some(state + len as u64)
+        } else {
+            Some(len as u64)
+        }
+    }
 }
 
 // 記号の連続はNG
@@ -175,12 +299,60 @@ fn validate_first_char_is_symbol(
     !is_symbol(append_word[0])
 }
 
-pub fn is_valid_password(
-    expected_memory: &Memory,
-    password: &[usize],
-    append_word: &[usize],
-) -> bool {
-    VALIDATORS
-        .iter()
-        .all(|validator| validator(expected_memory, password, append_word))
+#[test]
+fn register_custom_validator() {
+    use crate::domain::to_charcode_indices;
+
+    // 同じ単語を3回以上続けて使わない。状態は直前の単語と、続けて使った回数
+    struct NoTriple;
+    impl Validator for NoTriple {
+        fn name(&self) -> &str {
+            "no_triple"
+        }
+
+        fn append(
+            &self,
+            _expected_memory: &Memory,
+            _password: &[usize],
+            _segmentation: &[usize],
+            state: u64,
+            append_word: &[usize],
+        ) -> Option<u64> {
+            let word = append_word[0] as u64 + 1;
+            let count = if state >> 8 == word {
+                (state & 0xff) + 1
+            } else {
+                1
+            };
+            (count < 3).then_some(word << 8 | count)
+        }
+    }
+
+    let mut validators = Validators::default();
+    validators.register(Box::new(SuffixDigitsLength { max: 4 }));
+    validators.register(Box::new(NoTriple));
+    assert_eq!(
+        vec!["suffix_consecutive_digits_length", "no_triple"],
+        validators.names()
+    );
+
+    let dummy = Memory::new(8);
+    let accepts = |s: &str| {
+        let password = to_charcode_indices(s);
+        validators.accepts(&dummy, password.chunks(1))
+    };
+    assert!(accepts("1212"));
+    assert!(!accepts("12121"));
+    assert!(accepts("A1A1"));
+    assert!(!accepts("AAAK"));
+
+    let password = to_charcode_indices("1234");
+    let mut states = validators.initial_states();
+    states[0] = 4;
+    assert_eq!(
+        Err(0),
+        validators
+            .append(&dummy, &password, &[4], &states, &to_charcode_indices("5"))
+            .map(|s| s.to_vec())
+    );
 }
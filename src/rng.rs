@@ -0,0 +1,44 @@
+// 再現性のある乱数が欲しいだけなので、依存を増やさずSplitMix64で済ませる
+// https://prng.di.unimi.it/splitmix64.c
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // [0, n) の一様乱数
+    pub fn below(&mut self, n: u64) -> u64 {
+        assert!(n > 0);
+        // 剰余による偏りを棄却で取り除く
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let v = self.next_u64();
+            if v < zone {
+                return v % n;
+            }
+        }
+    }
+
+    // [0, n) の一様乱数。解の数はu64に収まらないことがある
+    pub fn below_u128(&mut self, n: u128) -> u128 {
+        assert!(n > 0);
+        let zone = u128::MAX - u128::MAX % n;
+        loop {
+            let v = (self.next_u64() as u128) << 64 | self.next_u64() as u128;
+            if v < zone {
+                return v % n;
+            }
+        }
+    }
+}
@@ -0,0 +1,310 @@
+// HuCardのROMイメージの解析
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+
+use crate::cpu::Memory;
+use crate::dict::parse_dict_words;
+use crate::domain::{is_vowel, to_charcode_indices, CHAR_CODES, CODE2CHAR};
+use crate::opt::{RomTargetsOpt, RomTextOpt};
+use crate::target::{load_registry, registry_path, write_registry, Target};
+
+pub fn read_rom(path: &Path) -> Vec<u8> {
+    let mut file = std::fs::File::open(path)
+        .unwrap_or_else(|e| panic!("cannot open {}: {}", path.display(), e));
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom).unwrap();
+
+    // 吸い出し機によっては先頭に512バイトのヘッダが付いている
+    if rom.len() % 0x2000 == 0x200 {
+        rom.drain(0..0x200);
+    }
+    rom
+}
+
+// "A9 ?? 8D F4 31" のような16進のバイト列。??は任意の1バイト
+pub fn parse_signature(s: &str) -> Result<Vec<Option<u8>>, String> {
+    s.split_whitespace()
+        .map(|t| {
+            if t == "??" {
+                Ok(None)
+            } else {
+                u8::from_str_radix(t, 16)
+                    .map(Some)
+                    .map_err(|e| format!("{}: {}", t, e))
+            }
+        })
+        .collect()
+}
+
+pub fn find_signature(rom: &[u8], signature: &[Option<u8>]) -> Vec<usize> {
+    if signature.is_empty() || rom.len() < signature.len() {
+        return Vec::new();
+    }
+
+    (0..=rom.len() - signature.len())
+        .filter(|&i| {
+            signature
+                .iter()
+                .zip(&rom[i..])
+                .all(|(s, &b)| s.is_none_or(|s| s == b))
+        })
+        .collect()
+}
+
+fn read_record(rom: &[u8], offset: usize) -> Option<Memory> {
+    let bytes = rom.get(offset..offset + 8)?;
+    Some(Memory::from_bytes(bytes.try_into().unwrap()))
+}
+
+// 既知のハッシュ値を目印に、前後に並んでいるそれらしいレコードをテーブルとして拾う
+fn scan_around_known(rom: &[u8], known: &[Memory]) -> BTreeMap<usize, Memory> {
+    let mut found = BTreeMap::new();
+    for memory in known {
+        let signature: Vec<_> = memory.to_bytes().iter().map(|&b| Some(b)).collect();
+        for offset in find_signature(rom, &signature) {
+            let mut begin = offset;
+            while begin >= 8 && read_record(rom, begin - 8).is_some_and(|m| m.validate().is_ok()) {
+                begin -= 8;
+            }
+
+            let mut offset = begin;
+            while let Some(memory) = read_record(rom, offset).filter(|m| m.validate().is_ok()) {
+                found.insert(offset, memory);
+                offset += 8;
+            }
+        }
+    }
+    found
+}
+
+pub fn scan_targets(rom: &[u8], opt: &RomTargetsOpt) -> BTreeMap<usize, Memory> {
+    let mut offsets = opt.offset.clone();
+    if let Some(signature) = &opt.signature {
+        let signature = parse_signature(signature).unwrap();
+        offsets.extend(
+            find_signature(rom, &signature)
+                .into_iter()
+                .map(|i| i + signature.len() + opt.skip),
+        );
+    }
+
+    if offsets.is_empty() {
+        let known: Vec<_> = load_registry(&registry_path())
+            .into_iter()
+            .filter_map(|t| t.memory.to_exact())
+            .collect();
+        return scan_around_known(rom, &known);
+    }
+
+    let mut found = BTreeMap::new();
+    for offset in offsets {
+        for i in 0..opt.count {
+            let offset = offset + i * 8;
+            match read_record(rom, offset) {
+                Some(memory) => {
+                    if let Err(e) = memory.validate() {
+                        eprintln!("skip 0x{:06X}: {}", offset, e);
+                        continue;
+                    }
+                    found.insert(offset, memory);
+                }
+                None => eprintln!("skip 0x{:06X}: out of rom", offset),
+            }
+        }
+    }
+    found
+}
+
+pub fn targets(opt: &RomTargetsOpt) {
+    let rom = read_rom(&opt.rom);
+    let found = scan_targets(&rom, opt);
+
+    for (offset, memory) in &found {
+        println!(
+            "0x{:06X}: {} (len {})",
+            offset,
+            memory.to_hex(),
+            memory.len()
+        );
+    }
+    eprintln!("{} targets found", found.len());
+
+    if let Some(path) = &opt.export {
+        let targets: Vec<_> = found
+            .into_iter()
+            .map(|(offset, memory)| {
+                let mut target = Target::new(format!("rom_{:06X}", offset), memory.into());
+                target.notes = Some(format!("{} offset 0x{:06X}", opt.rom.display(), offset));
+                target
+            })
+            .collect();
+        write_registry(path, &targets);
+    }
+}
+
+pub type TextTable = [Option<char>; 0x100];
+
+// パスワードに使える文字の表をそのまま使う
+pub fn default_text_table() -> TextTable {
+    let mut table = [None; 0x100];
+    for &c in &CHAR_CODES {
+        table[c as usize] = Some(CODE2CHAR[c as usize]);
+    }
+    table
+}
+
+// "XX=文字" の行を並べた文字コード表(いわゆる.tbl形式)。`;`以降はコメント
+pub fn parse_text_table(s: &str) -> Result<TextTable, String> {
+    let mut table = [None; 0x100];
+    for (i, line) in s.lines().enumerate() {
+        let line = line.split(';').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (code, c) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected XX=C", i + 1))?;
+        let code =
+            u8::from_str_radix(code.trim(), 16).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let mut chars = c.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => table[code as usize] = Some(c),
+            _ => return Err(format!("line {}: expected a single character", i + 1)),
+        }
+    }
+    Ok(table)
+}
+
+fn is_password_char(c: char) -> bool {
+    c != '?' && CODE2CHAR.contains(&c)
+}
+
+// 単語として拾う価値がありそうか
+// ROMのデータ領域も文字として読めてしまうので、同じ文字の連続や母音のない並びを除く
+fn looks_like_word(word: &str) -> bool {
+    let indices = to_charcode_indices(word);
+    let distinct: HashSet<_> = indices.iter().collect();
+    distinct.len() >= 2
+        && !indices.windows(3).any(|w| w[0] == w[1] && w[1] == w[2])
+        && indices.iter().any(|&i| is_vowel(i))
+}
+
+// 文字コード表で読める、パスワードに使える文字の連続を数える
+pub fn harvest_strings(
+    rom: &[u8],
+    table: &TextTable,
+    min_len: usize,
+    max_len: usize,
+) -> HashMap<String, usize> {
+    let mut found = HashMap::new();
+    let mut run = String::new();
+    let mut run_len = 0;
+    for b in rom.iter().map(Some).chain(std::iter::once(None)) {
+        match b
+            .and_then(|&b| table[b as usize])
+            .filter(|&c| is_password_char(c))
+        {
+            Some(c) => {
+                run.push(c);
+                run_len += 1;
+            }
+            None => {
+                if min_len <= run_len && run_len <= max_len && looks_like_word(&run) {
+                    *found.entry(run.clone()).or_insert(0) += 1;
+                }
+                run.clear();
+                run_len = 0;
+            }
+        }
+    }
+    found
+}
+
+pub fn text(opt: &RomTextOpt) {
+    let rom = read_rom(&opt.rom);
+    let table = match &opt.table {
+        Some(path) => {
+            let s = std::fs::read_to_string(path)
+                .unwrap_or_else(|e| panic!("cannot open {}: {}", path.display(), e));
+            parse_text_table(&s).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+        }
+        None => default_text_table(),
+    };
+
+    let mut known = HashSet::new();
+    let exclude = if opt.exclude.is_empty() {
+        vec![Path::new("./dict.txt").to_path_buf()]
+    } else {
+        opt.exclude.clone()
+    };
+    for path in exclude {
+        if let Ok(s) = std::fs::read_to_string(&path) {
+            let words = parse_dict_words(&s).unwrap_or_else(|e| panic!("{}:{}", path.display(), e));
+            known.extend(words.into_iter().map(str::to_string));
+        }
+    }
+
+    let mut found: Vec<_> = harvest_strings(&rom, &table, opt.min_len, opt.max_len)
+        .into_iter()
+        .filter(|(word, _)| !known.contains(word))
+        .collect();
+    // よく出てくるもの、長いものほど上位
+    found.sort_by(|(w1, c1), (w2, c2)| {
+        c2.cmp(c1)
+            .then(w2.chars().count().cmp(&w1.chars().count()))
+            .then(w1.cmp(w2))
+    });
+    if let Some(limit) = opt.limit {
+        found.truncate(limit);
+    }
+
+    let mut s = String::new();
+    for (word, count) in &found {
+        eprintln!("{:6} {}", count, word);
+        s += word;
+        s += "\n";
+    }
+    eprintln!("{} words harvested", found.len());
+
+    match &opt.output {
+        Some(path) => std::fs::write(path, s).unwrap(),
+        None => print!("{}", s),
+    }
+}
+
+#[test]
+fn harvest_from_custom_table() {
+    let table = parse_text_table("10=K\n11=I\n12=D\n13=A\n14=B ; comment\n").unwrap();
+    let rom = [
+        0xFF, 0x10, 0x11, 0x12, 0xFF, 0x10, 0x11, 0x12, 0x00, 0x13, 0x13, 0x13, 0x14, 0xFF,
+    ];
+    let found = harvest_strings(&rom, &table, 3, 14);
+    assert_eq!(Some(&2), found.get("KID"));
+    // 同じ文字が3回続くものは拾わない
+    assert_eq!(1, found.len());
+}
+
+#[test]
+fn scan_table_around_known_target() {
+    use crate::cpu::forward_word;
+
+    let kid: Memory = "00 51 03 3A E9 08 23 07".parse().unwrap();
+    let mut rom = vec![0xFF; 0x40];
+    rom.extend([0xDC, 0xD9, 0x08, 0xA3, 0xE3, 0x17, 0x28, 0x15]);
+    rom.extend(kid.to_bytes());
+    let mut memory = Memory::new(12);
+    forward_word(&mut memory, &to_charcode_indices("HENTAIOSUGI1"));
+    rom.extend(memory.to_bytes());
+    // 長さとbitはもっともらしいが、xorが文字コードの範囲を超えている
+    rom.extend([0x12, 0x34, 0x0C, 0x56, 0x78, 0x9A, 0xBC, 0x20]);
+    rom.extend([0xFF; 0x10]);
+
+    let found = scan_around_known(&rom, &[kid]);
+    assert_eq!(
+        vec![0x40, 0x48, 0x50],
+        found.keys().copied().collect::<Vec<_>>()
+    );
+    assert_eq!(12, found[&0x50].len());
+}
@@ -0,0 +1,234 @@
+// ローマ字の音節の並びを受理する決定性オートマトン
+//
+// 音節(ヘボン式と訓令式の両方)のトライを作り、音節の終わりから根に戻るNFAにしてから決定化する
+// - 撥音は"N"を1音節として扱う。"NN"や、ヘボン式のB, M, Pの前の"M"も受理する
+// - 促音は次の音節の子音を重ねたもの("KK", "SS", ...)と、ヘボン式の"TCH"
+// - 長音は母音を続けて書くか、記号の"-"で書く
+// 数字、記号、ゲーム独自の文字(n, m, c)は音節の区切りでだけ受理し、その後は音節の先頭に戻る
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use crate::cpu::Memory;
+use crate::domain::{CHAR_CODES, CODE2CHAR};
+use crate::pruning::Validator;
+
+const DEAD: u32 = u32::MAX;
+
+const VOWELS: &str = "AIUEO";
+
+fn syllables() -> Vec<String> {
+    let mut syllables: Vec<String> = VOWELS.chars().map(String::from).collect();
+    // 五十音の行。SI, TI, TU, HU, ZI, DI, DUは訓令式
+    for c in "KSTNHMRGZDBP".chars() {
+        for v in VOWELS.chars() {
+            syllables.push(format!("{}{}", c, v));
+        }
+    }
+    // 拗音。SY, TY, ZYは訓令式、SH, CH, Jはヘボン式
+    for c in ["K", "S", "T", "N", "H", "M", "R", "G", "Z", "D", "B", "P"] {
+        for v in "AUO".chars() {
+            syllables.push(format!("{}Y{}", c, v));
+        }
+    }
+    for c in ["SH", "CH", "J"] {
+        for v in VOWELS.chars() {
+            syllables.push(format!("{}{}", c, v));
+        }
+    }
+    // 外来語の音を含む残り
+    for s in [
+        "YA", "YU", "YO", "WA", "WO", "TSU", "FA", "FI", "FU", "FE", "FO", "VA", "VI", "VU", "VE",
+        "VO", "N",
+    ] {
+        syllables.push(s.to_string());
+    }
+    syllables
+}
+
+#[derive(Default)]
+struct NfaNode {
+    edges: Vec<(char, usize)>,
+    // ここで音節が終わる
+    terminal: bool,
+}
+
+struct Nfa {
+    nodes: Vec<NfaNode>,
+}
+
+impl Nfa {
+    const ROOT: usize = 0;
+
+    fn new() -> Self {
+        let mut nfa = Self {
+            nodes: vec![NfaNode::default()],
+        };
+        for s in syllables() {
+            let mut node = Self::ROOT;
+            for c in s.chars() {
+                node = nfa.child(node, c);
+            }
+            nfa.nodes[node].terminal = true;
+        }
+
+        // 促音。根から子音を読み、次に同じ子音(TならCも)で始まる音節に進む
+        for c in "KSTPCGZDBHFJR".chars() {
+            let next: &[char] = if c == 'T' { &['T', 'C'] } else { &[c] };
+            nfa.pending(c, next);
+        }
+        // ヘボン式の撥音"M"
+        nfa.pending('M', &['B', 'M', 'P']);
+        nfa
+    }
+
+    fn child(&mut self, node: usize, c: char) -> usize {
+        if let Some(&(_, next)) = self.nodes[node].edges.iter().find(|&&(d, _)| d == c) {
+            return next;
+        }
+        self.nodes.push(NfaNode::default());
+        let next = self.nodes.len() - 1;
+        self.nodes[node].edges.push((c, next));
+        next
+    }
+
+    // 根からcを読んだあと、nextのどれかで始まる音節しか続かないノード
+    fn pending(&mut self, c: char, next: &[char]) {
+        let edges = next
+            .iter()
+            .map(|&d| (d, self.child(Self::ROOT, d)))
+            .collect();
+        self.nodes.push(NfaNode {
+            edges,
+            terminal: false,
+        });
+        let node = self.nodes.len() - 1;
+        self.nodes[Self::ROOT].edges.push((c, node));
+    }
+
+    // 音節が終わったノードを含めば、根も含める
+    fn closure(&self, mut set: BTreeSet<usize>) -> BTreeSet<usize> {
+        if set.iter().any(|&n| self.nodes[n].terminal) {
+            set.insert(Self::ROOT);
+        }
+        set
+    }
+
+    fn step(&self, set: &BTreeSet<usize>, c: char) -> BTreeSet<usize> {
+        if !c.is_ascii_uppercase() {
+            // 音節の区切りでだけ受理する
+            return if set.contains(&Self::ROOT) {
+                BTreeSet::from([Self::ROOT])
+            } else {
+                BTreeSet::new()
+            };
+        }
+        let next = set
+            .iter()
+            .flat_map(|&n| &self.nodes[n].edges)
+            .filter(|&&(d, _)| d == c)
+            .map(|&(_, m)| m)
+            .collect();
+        self.closure(next)
+    }
+}
+
+pub struct Romaji {
+    // [状態][文字の番号]
+    next: Vec<[u32; CHAR_CODES.len()]>,
+    // 音節の区切りにいる
+    accepting: Vec<bool>,
+}
+
+impl Romaji {
+    pub fn new() -> Self {
+        let nfa = Nfa::new();
+        let chars: Vec<char> = CHAR_CODES.iter().map(|&c| CODE2CHAR[c as usize]).collect();
+
+        let start = nfa.closure(BTreeSet::from([Nfa::ROOT]));
+        let mut ids = HashMap::from([(start.clone(), 0)]);
+        let mut sets = vec![start];
+        let mut queue = VecDeque::from([0]);
+        let mut next = Vec::new();
+        while let Some(id) = queue.pop_front() {
+            let mut row = [DEAD; CHAR_CODES.len()];
+            for (i, &c) in chars.iter().enumerate() {
+                let set = nfa.step(&sets[id], c);
+                if set.is_empty() {
+                    continue;
+                }
+                row[i] = *ids.entry(set.clone()).or_insert_with(|| {
+                    sets.push(set);
+                    queue.push_back(sets.len() - 1);
+                    (sets.len() - 1) as u32
+                });
+            }
+            // 番号の順にキューから取り出すので、idはnext.len()と同じ
+            next.push(row);
+        }
+
+        let accepting = sets.iter().map(|s| s.contains(&Nfa::ROOT)).collect();
+        Self { next, accepting }
+    }
+}
+
+// 状態はオートマトンの状態の番号。パスワードの最後は音節の区切りで終わらなければならない
+impl Validator for Romaji {
+    fn name(&self) -> &str {
+        "romaji"
+    }
+
+    fn append(
+        &self,
+        expected_memory: &Memory,
+        password: &[usize],
+        _segmentation: &[usize],
+        state: u64,
+        append_word: &[usize],
+    ) -> Option<u64> {
+        let mut state = state as u32;
+        for &c in append_word {
+            state = self.next[state as usize][c];
+            if state == DEAD {
+                return None;
+            }
+        }
+        if password.len() + append_word.len() == expected_memory.len()
+            && !self.accepting[state as usize]
+        {
+            return None;
+        }
+        Some(state as u64)
+    }
+}
+
+#[test]
+fn accept_romaji_syllables() {
+    use crate::domain::to_charcode_indices;
+
+    let romaji = Romaji::new();
+    let accepts = |s: &str| {
+        let password = to_charcode_indices(s);
+        let memory = Memory::new(s.len() as u8);
+        romaji
+            .append(&memory, &[], &[], romaji.initial_state(), &password)
+            .is_some()
+    };
+
+    for s in [
+        "HENTAIOSUGI",
+        "KONNICHIWA",
+        "TSUKUSHI",
+        "SHIMBUN",
+        "MATCHA",
+        "KITTE",
+        "TOUKYOU",
+        "SYUUTEN",
+        "FUJI-SAN",
+        "818-6104",
+        "RAMEN2",
+    ] {
+        assert!(accepts(s), "{}", s);
+    }
+    for s in ["XYZ", "KTA", "LALA", "SHYA", "KAK", "KY", "TOKY0"] {
+        assert!(!accepts(s), "{}", s);
+    }
+}
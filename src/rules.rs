@@ -0,0 +1,263 @@
+// 枝刈りのルールファイル(rules.toml)を読んでバリデータにする
+use serde::Deserialize;
+
+use crate::cpu::Memory;
+use crate::domain::{is_alpha, is_number, is_symbol, is_vowel, try_to_charcode_indices};
+use crate::pruning::Validator;
+
+// 組み込みのルール。--rulesを指定しなければこれを使う
+pub const DEFAULT_RULES: &str = include_str!("../rules.toml");
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Class {
+    Vowel,
+    Consonant,
+    Alpha,
+    NonVowel,
+    Number,
+    Symbol,
+    Any,
+}
+
+impl Class {
+    fn contains(self, c: usize) -> bool {
+        match self {
+            Class::Vowel => is_vowel(c),
+            Class::Consonant => is_alpha(c) && !is_vowel(c),
+            Class::Alpha => is_alpha(c),
+            Class::NonVowel => !is_vowel(c),
+            Class::Number => is_number(c),
+            Class::Symbol => is_symbol(c),
+            Class::Any => true,
+        }
+    }
+}
+
+// ルールを調べるつなぎ目
+// 1文字の単語どうしのつなぎ目は、1文字ずつ綴った1つの言葉の中とみなす
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    // 1文字の単語どうしのつなぎ目だけ
+    Within,
+    // どちらかが2文字以上の単語のつなぎ目だけ
+    Boundary,
+    #[default]
+    Both,
+}
+
+impl Scope {
+    fn applies(self, segmentation: &[usize], append_word: &[usize]) -> bool {
+        let boundary = segmentation.last().is_some_and(|&l| l > 1) || append_word.len() > 1;
+        match self {
+            Scope::Within => !boundary,
+            Scope::Boundary => boundary,
+            Scope::Both => true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RuleKind {
+    ForbidAfter {
+        before: Class,
+        after: Class,
+    },
+    NoRepeat {
+        class: Class,
+    },
+    MaxRun {
+        class: Class,
+        #[serde(default)]
+        next: Option<Class>,
+        max: usize,
+    },
+    ForbiddenBigrams {
+        bigrams: Vec<String>,
+        // 文字の番号にしたもの。読み込むときに埋める
+        #[serde(skip)]
+        indices: Vec<[usize; 2]>,
+    },
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default)]
+    pub scope: Scope,
+    #[serde(flatten)]
+    pub kind: RuleKind,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+pub fn parse_rules(s: &str) -> Result<Vec<Rule>, String> {
+    let mut file: RuleFile = toml::from_str(s).map_err(|e| e.to_string())?;
+    for rule in &mut file.rule {
+        if let RuleKind::ForbiddenBigrams { bigrams, indices } = &mut rule.kind {
+            for b in bigrams.iter() {
+                match try_to_charcode_indices(b).as_deref() {
+                    Some(&[c0, c1]) => indices.push([c0, c1]),
+                    _ => {
+                        return Err(format!(
+                            "{}: bigram must be 2 password characters: {}",
+                            rule.name, b
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    Ok(file.rule)
+}
+
+// パスワードの末尾と足す単語の先頭を見る。max_runだけは末尾に続いているclassの文字の数を状態に持つ
+// scopeに当てはまらないつなぎ目は調べず、max_runの状態だけ更新する
+impl Validator for Rule {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn append(
+        &self,
+        _expected_memory: &Memory,
+        password: &[usize],
+        segmentation: &[usize],
+        state: u64,
+        append_word: &[usize],
+    ) -> Option<u64> {
+        let first = append_word[0];
+        let applies = self.scope.applies(segmentation, append_word);
+        let last = password.last().filter(|_| applies);
+        match &self.kind {
+            RuleKind::ForbidAfter { before, after } => match last {
+                Some(&c) if before.contains(c) && after.contains(first) => None,
+                _ => Some(0),
+            },
+            RuleKind::NoRepeat { class } => match last {
+                Some(&c) if class.contains(c) && c == first => None,
+                _ => Some(0),
+            },
+            RuleKind::MaxRun { class, next, max } => {
+                if applies && next.unwrap_or(*class).contains(first) && state >= *max as u64 {
+                    return None;
+                }
+                let run = append_word
+                    .iter()
+                    .rev()
+                    .take_while(|&&c| class.contains(c))
+                    .count() as u64;
+                if run == append_word.len() as u64 {
+                    Some(state + run)
+                } else {
+                    Some(run)
+                }
+            }
+            RuleKind::ForbiddenBigrams { indices, .. } => match last {
+                Some(&c) if indices.contains(&[c, first]) => None,
+                _ => Some(0),
+            },
+        }
+    }
+}
+
+#[test]
+fn default_rules_prune_unnatural_japanese() {
+    use crate::domain::to_charcode_indices;
+    use crate::pruning::Validators;
+
+    let mut validators = Validators::default();
+    for rule in parse_rules(DEFAULT_RULES).unwrap() {
+        validators.register(Box::new(rule));
+    }
+    let dummy = Memory::new(8);
+    let valid = |password: &str, word: &str| {
+        let password = to_charcode_indices(password);
+        let word = to_charcode_indices(word);
+        validators.accepts(&dummy, password.chunks(1).chain([&word[..]]))
+    };
+
+    assert!(valid("", "K"));
+    assert!(valid("KA", "!"));
+    assert!(!valid("K", "!"));
+    assert!(!valid("N", "1"));
+    assert!(!valid("SAS", "S"));
+    assert!(valid("AIU", "K"));
+    assert!(!valid("AIU", "E"));
+    assert!(!valid("AST", "R"));
+    // 子音の連続には数字や記号も数えるが、足すのが子音のときだけ
+    assert!(!valid("A12", "K"));
+    assert!(valid("A12", "3"));
+}
+
+#[test]
+fn parse_custom_rules() {
+    use crate::domain::to_charcode_indices;
+
+    let rules = parse_rules(
+        r#"
+[[rule]]
+name = "digits"
+kind = "max_run"
+class = "number"
+max = 4
+
+[[rule]]
+name = "bigrams"
+kind = "forbidden_bigrams"
+bigrams = ["QX", "XQ"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        RuleKind::MaxRun {
+            class: Class::Number,
+            next: None,
+            max: 4
+        },
+        rules[0].kind
+    );
+
+    let dummy = Memory::new(8);
+    let append = |password: &str, word: &str| {
+        let password = to_charcode_indices(password);
+        let segmentation = vec![1; password.len()];
+        rules[1].append(
+            &dummy,
+            &password,
+            &segmentation,
+            0,
+            &to_charcode_indices(word),
+        )
+    };
+    assert_eq!(None, append("AQ", "X"));
+    assert_eq!(Some(0), append("AX", "X"));
+
+    let within = parse_rules(
+        "[[rule]]\nname = \"same\"\nkind = \"no_repeat\"\nclass = \"alpha\"\nscope = \"within\"\n",
+    )
+    .unwrap();
+    let append = |segmentation: &[usize], word: &str| {
+        let password = to_charcode_indices("MITA");
+        within[0].append(
+            &dummy,
+            &password,
+            segmentation,
+            0,
+            &to_charcode_indices(word),
+        )
+    };
+    // 1文字ずつ綴ったところでは弾き、辞書の単語どうしのつなぎ目では弾かない
+    assert_eq!(None, append(&[1, 1, 1, 1], "A"));
+    assert_eq!(Some(0), append(&[4], "AKI"));
+    assert_eq!(Some(0), append(&[4], "A"));
+
+    let bad = "[[rule]]\nname = \"b\"\nkind = \"forbidden_bigrams\"\nbigrams = [\"QX\", \"Q\"]\n";
+    assert!(parse_rules(bad).is_err());
+}
@@ -0,0 +1,207 @@
+// 既知のターゲットの登録簿(targets.toml)
+//
+//   [[target]]
+//   name = "11"
+//   bytes = "64 98 0B 15 91 18 B1 15"
+//   length = 11
+//   solution = "HENTAIOSUGI"
+//   notes = "yokai03.exe 64 98 0B 15 91 18 B1 15"
+//   effect = "..."
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::PartialMemory;
+use crate::opt::OPT;
+
+pub const DEFAULT_REGISTRY: &str = "./targets.toml";
+
+// 何も指定されなかったときの探索対象
+const DEFAULT_TARGET: &str = "14";
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    name: String,
+    bytes: String,
+    length: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    solution: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    effect: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Registry {
+    #[serde(default)]
+    target: Vec<Entry>,
+}
+
+pub struct Target {
+    pub name: String,
+    // 分からないバイトは??で書ける
+    pub memory: PartialMemory,
+    // 判明しているパスワード
+    pub solution: Option<String>,
+    // 出典などのメモ
+    pub notes: Option<String>,
+    // ゲーム中での効果
+    pub effect: Option<String>,
+}
+
+impl Target {
+    pub fn new(name: String, memory: PartialMemory) -> Self {
+        Self {
+            name,
+            memory,
+            solution: None,
+            notes: None,
+            effect: None,
+        }
+    }
+
+    fn from_entry(entry: Entry) -> Result<Self, String> {
+        let memory: PartialMemory = entry.bytes.parse()?;
+        memory.validate()?;
+        if memory.len() != entry.length {
+            return Err(format!(
+                "length is {} but bytes say {}",
+                entry.length,
+                memory.len()
+            ));
+        }
+        if let Some(solution) = &entry.solution {
+            memory.verify_solution(solution)?;
+        }
+
+        Ok(Self {
+            name: entry.name,
+            memory,
+            solution: entry.solution,
+            notes: entry.notes,
+            effect: entry.effect,
+        })
+    }
+
+    fn to_entry(&self) -> Entry {
+        Entry {
+            name: self.name.clone(),
+            bytes: self.memory.to_hex(),
+            length: self.memory.len(),
+            solution: self.solution.clone(),
+            notes: self.notes.clone(),
+            effect: self.effect.clone(),
+        }
+    }
+}
+
+pub fn parse_registry(s: &str) -> Result<Vec<Target>, String> {
+    let registry: Registry = toml::from_str(s).map_err(|e| e.to_string())?;
+
+    let mut names = HashSet::new();
+    let mut targets = Vec::new();
+    for entry in registry.target {
+        let name = entry.name.clone();
+        if !names.insert(name.clone()) {
+            return Err(format!("{}: duplicated name", name));
+        }
+        targets.push(Target::from_entry(entry).map_err(|e| format!("{}: {}", name, e))?);
+    }
+    Ok(targets)
+}
+
+pub fn load_registry(path: &Path) -> Vec<Target> {
+    let s = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("cannot open {}: {}", path.display(), e));
+    parse_registry(&s).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+pub fn write_registry(path: &Path, targets: &[Target]) {
+    let registry = Registry {
+        target: targets.iter().map(Target::to_entry).collect(),
+    };
+    std::fs::write(path, toml::to_string(&registry).unwrap()).unwrap();
+}
+
+pub fn registry_path() -> PathBuf {
+    OPT.target_file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_REGISTRY))
+}
+
+// --targetで指定された探索対象(複数指定可)
+// 16進8バイト(??可)として読めなければ登録簿の中から名前で探す
+pub fn selected_targets() -> Vec<PartialMemory> {
+    let default = [DEFAULT_TARGET.to_string()];
+    let names = if OPT.target.is_empty() {
+        &default[..]
+    } else {
+        &OPT.target[..]
+    };
+
+    let mut registry = None;
+    names
+        .iter()
+        .map(|target| {
+            if let Ok(memory) = target.parse::<PartialMemory>() {
+                memory.validate().unwrap();
+                return memory;
+            }
+
+            let path = registry_path();
+            registry
+                .get_or_insert_with(|| load_registry(&path))
+                .iter()
+                .find(|t| &t.name == target)
+                .unwrap_or_else(|| panic!("target {} is not found in {}", target, path.display()))
+                .memory
+                .clone()
+        })
+        .collect()
+}
+
+// 登録簿を検証して一覧を表示する
+pub fn list_targets() {
+    let path = registry_path();
+    for target in load_registry(&path) {
+        println!(
+            "{:12} {} {:>2}文字 {}",
+            target.name,
+            target.memory.to_hex(),
+            target.memory.len(),
+            target.solution.as_deref().unwrap_or("(未解決)")
+        );
+        for (label, s) in [("notes", &target.notes), ("effect", &target.effect)] {
+            if let Some(s) = s {
+                println!("{:12}   {}: {}", "", label, s);
+            }
+        }
+    }
+}
+
+#[test]
+fn shipped_registry_is_valid() {
+    let targets = load_registry(Path::new(DEFAULT_REGISTRY));
+    assert!(targets.iter().any(|t| t.name == DEFAULT_TARGET));
+    assert_eq!(
+        Some("HENTAIOSUGI"),
+        targets
+            .iter()
+            .find(|t| t.name == "11")
+            .and_then(|t| t.solution.as_deref())
+    );
+}
+
+#[test]
+fn reject_wrong_solution() {
+    let s = "[[target]]\n\
+             name = \"kid\"\n\
+             bytes = \"00 51 03 3A E9 08 23 07\"\n\
+             length = 3\n\
+             solution = \"KIE\"\n";
+    assert!(parse_registry(s).is_err());
+    assert!(parse_registry(&s.replace("KIE", "KID")).is_ok());
+    assert!(parse_registry(&s.replace("length = 3", "length = 4")).is_err());
+}